@@ -0,0 +1,111 @@
+use std::{
+    io::{self, ErrorKind},
+    time::Duration,
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{
+    buffered_stream::{short_read, truncated_frame, ReadBuffer, CHUNK_SIZE},
+    codec::Decoder,
+};
+
+// the async counterpart of BufferedStream, for peer connections running as tasks on the runtime
+pub struct AsyncBufferedStream<T: AsyncRead + Unpin> {
+    reader: T,
+    buffer: ReadBuffer,
+    // how long a single read from reader may take before failing with ErrorKind::TimedOut
+    read_timeout: Option<Duration>,
+}
+
+impl<T: AsyncRead + Unpin> AsyncBufferedStream<T> {
+    pub fn new(reader: T) -> AsyncBufferedStream<T> {
+        AsyncBufferedStream {
+            reader,
+            buffer: ReadBuffer::new(),
+            read_timeout: None,
+        }
+    }
+
+    pub fn set_read_timeout(&mut self, read_timeout: Option<Duration>) {
+        self.read_timeout = read_timeout;
+    }
+
+    // fails with ErrorKind::UnexpectedEof if the stream ends before n bytes could be read
+    pub async fn read_n_bytes(&mut self, n: usize) -> io::Result<Vec<u8>> {
+        let mut result = self.buffer.take(n);
+        let mut filled = result.len();
+        result.resize(n, 0);
+        while filled < n {
+            // reads that wouldn't fit the buffer anyway go straight into the result
+            let read = if n - filled >= CHUNK_SIZE {
+                with_timeout(self.read_timeout, self.reader.read(&mut result[filled..])).await?
+            } else {
+                let available = self.fill_buf().await?;
+                let read = available.len().min(n - filled);
+                result[filled..filled + read].copy_from_slice(&available[..read]);
+                self.buffer.consume(read);
+                read
+            };
+            if read == 0 {
+                return Err(short_read(filled, n));
+            }
+            filled += read;
+        }
+        Ok(result)
+    }
+
+    pub async fn read_byte(&mut self) -> io::Result<u8> {
+        let byte = self.peek_byte().await?.ok_or_else(|| short_read(0, 1))?;
+        self.buffer.consume(1);
+        Ok(byte)
+    }
+
+    // None if the stream has ended cleanly, i.e. there is nothing left to read
+    pub async fn peek_byte(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.fill_buf().await?.first().cloned())
+    }
+
+    // Ok(None) once the stream has ended cleanly between two frames
+    pub async fn read_frame<D: Decoder>(
+        &mut self,
+        codec: &mut D,
+    ) -> Result<Option<D::Item>, D::Error> {
+        loop {
+            if let Some(frame) = codec.decode(self.buffer.bytes_mut())? {
+                return Ok(Some(frame));
+            }
+            if self.read_more().await? == 0 {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                return Err(truncated_frame(self.buffer.available().len()).into());
+            }
+        }
+    }
+
+    async fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.buffer.is_empty() {
+            self.read_more().await?;
+        }
+        Ok(self.buffer.available())
+    }
+
+    // appends whatever the reader has ready to the buffer, 0 meaning the stream has ended
+    async fn read_more(&mut self) -> io::Result<usize> {
+        let buffer = self.buffer.bytes_mut();
+        with_timeout(self.read_timeout, self.reader.read_buf(buffer)).await
+    }
+}
+
+async fn with_timeout(
+    read_timeout: Option<Duration>,
+    read: impl std::future::Future<Output = io::Result<usize>>,
+) -> io::Result<usize> {
+    match read_timeout {
+        Some(read_timeout) => tokio::time::timeout(read_timeout, read)
+            .await
+            .map_err(|_| io::Error::new(ErrorKind::TimedOut, "read timed out"))?,
+        None => read.await,
+    }
+}
@@ -1,3 +1,7 @@
 pub mod bdecoder;
 pub mod bencoder;
 pub mod btype;
+pub mod bvalue;
+pub mod de;
+pub mod query;
+pub mod ser;
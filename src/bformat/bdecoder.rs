@@ -1,61 +1,466 @@
-use std::{collections::HashMap, io::Read};
+use std::{
+    collections::BTreeMap,
+    io::{self, BufRead, ErrorKind, Read},
+};
 
-use super::btype::BType;
+use thiserror::Error;
+
+use super::{
+    btype::{BMap, BType},
+    bvalue::{BDict, BValue},
+};
 use crate::buffered_stream::BufferedStream;
 
-// todo don't save everything in serde_json::Value. Need to store some strings as Vec<u8> if they fail to convert from utf8
-pub fn decode<T: Read>(buf_stream: &mut BufferedStream<T>) -> BType {
-    let first_byte = buf_stream.peek_byte().unwrap();
+#[derive(Debug, Error)]
+pub enum DecodeError {
+    #[error("unexpected end of input at byte {offset}")]
+    UnexpectedEof { offset: usize },
+    #[error("failed to read input at byte {offset}")]
+    Io { offset: usize, source: io::Error },
+    #[error("unable to determine bencode type from byte {byte:#04x} at byte {offset}")]
+    InvalidType { byte: u8, offset: usize },
+    #[error("invalid string length at byte {offset}")]
+    InvalidLength { offset: usize },
+    #[error("invalid integer at byte {offset}")]
+    InvalidNumber { offset: usize },
+    #[error("non-canonical string length at byte {offset}")]
+    NonCanonicalLength { offset: usize },
+    #[error("non-canonical integer at byte {offset}")]
+    NonCanonicalNumber { offset: usize },
+    #[error("dictionary key out of order at byte {offset}")]
+    UnsortedKey { offset: usize },
+    #[error("duplicate dictionary key at byte {offset}")]
+    DuplicateKey { offset: usize },
+    #[error("trailing data after value at byte {offset}")]
+    TrailingData { offset: usize },
+    #[error("lists and dictionaries nested deeper than {max_depth} at byte {offset}")]
+    TooDeep { max_depth: usize, offset: usize },
+    #[error("string of {length} bytes is longer than {max_string_length} at byte {offset}")]
+    StringTooLong {
+        length: usize,
+        max_string_length: usize,
+        offset: usize,
+    },
+    #[error("input is larger than {max_size} bytes at byte {offset}")]
+    TooLarge { max_size: usize, offset: usize },
+}
+
+pub struct DecodeOptions {
+    // reject anything that isn't the single canonical encoding of a value: leading zeros,
+    // negative zero, unsorted or duplicate dictionary keys and data after the top level value
+    pub strict: bool,
+    // the limits below are checked before anything is allocated, so untrusted input (peers,
+    // trackers) can't exhaust the stack or memory
+    pub max_depth: usize,
+    pub max_string_length: usize,
+    pub max_size: usize,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> DecodeOptions {
+        DecodeOptions {
+            strict: false,
+            max_depth: 64,
+            // big enough for the pieces string of any reasonable torrent
+            max_string_length: 32 * 1024 * 1024,
+            max_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl DecodeOptions {
+    pub fn strict() -> DecodeOptions {
+        DecodeOptions {
+            strict: true,
+            ..DecodeOptions::default()
+        }
+    }
+}
+
+pub fn decode<T: Read>(buf_stream: &mut BufferedStream<T>) -> Result<BType, DecodeError> {
+    decode_with_options(buf_stream, &DecodeOptions::default())
+}
+
+pub fn decode_with_options<T: Read>(
+    buf_stream: &mut BufferedStream<T>,
+    options: &DecodeOptions,
+) -> Result<BType, DecodeError> {
+    let value = decode_value(buf_stream, options, 0)?;
+    check_end(buf_stream, options)?;
+    Ok(value)
+}
+
+// depth is the number of lists and dictionaries the value is nested in
+fn decode_value<T: Read>(
+    buf_stream: &mut BufferedStream<T>,
+    options: &DecodeOptions,
+    depth: usize,
+) -> Result<BType, DecodeError> {
+    let first_byte = peek_byte(buf_stream)?;
     if first_byte.is_ascii_digit() {
-        BType::Bytes(decode_bytes(buf_stream))
+        Ok(BType::Bytes(decode_bytes(buf_stream, options)?))
     } else if first_byte == b'i' {
-        BType::Number(decode_number(buf_stream))
+        Ok(BType::Number(decode_number(buf_stream, options)?))
     } else if first_byte == b'l' {
-        BType::List(decode_list(buf_stream))
+        check_depth(depth, buf_stream.position(), options)?;
+        Ok(BType::List(decode_list(buf_stream, options, depth + 1)?))
     } else if first_byte == b'd' {
-        BType::Map(decode_map(buf_stream))
+        check_depth(depth, buf_stream.position(), options)?;
+        Ok(BType::Map(decode_dict(buf_stream, options, depth + 1)?))
     } else {
-        panic!(
-            "Unable to determine bencode type from first byte: {}",
-            first_byte
-        );
+        Err(DecodeError::InvalidType {
+            byte: first_byte,
+            offset: buf_stream.position(),
+        })
     }
 }
 
-fn decode_bytes<T: Read>(buf_stream: &mut BufferedStream<T>) -> Vec<u8> {
-    let length = String::from_utf8(buf_stream.read_until(b':').unwrap())
-        .unwrap()
-        .parse::<usize>()
-        .unwrap();
-    return buf_stream.read_n_bytes(length).unwrap();
+fn decode_bytes<T: Read>(
+    buf_stream: &mut BufferedStream<T>,
+    options: &DecodeOptions,
+) -> Result<Vec<u8>, DecodeError> {
+    let offset = buf_stream.position();
+    let length_bytes = read_until(buf_stream, b':', options)?;
+    if options.strict && !is_canonical_length(&length_bytes) {
+        return Err(DecodeError::NonCanonicalLength { offset });
+    }
+    let length = String::from_utf8(length_bytes)
+        .ok()
+        .and_then(|length| length.parse::<usize>().ok())
+        .ok_or(DecodeError::InvalidLength { offset })?;
+    let offset = buf_stream.position();
+    check_string(length, offset, options)?;
+    buf_stream
+        .read_n_bytes(length)
+        .map_err(|err| read_error(err, offset))
 }
 
-fn decode_number<T: Read>(buf_stream: &mut BufferedStream<T>) -> i128 {
-    buf_stream.read_byte(); // skip the 'i'
-    String::from_utf8(buf_stream.read_until(b'e').unwrap())
-        .unwrap()
-        .parse::<i128>()
-        .unwrap()
+fn decode_number<T: Read>(
+    buf_stream: &mut BufferedStream<T>,
+    options: &DecodeOptions,
+) -> Result<i128, DecodeError> {
+    read_byte(buf_stream)?; // skip the 'i'
+    let offset = buf_stream.position();
+    let number_bytes = read_until(buf_stream, b'e', options)?;
+    if options.strict && !is_canonical_number(&number_bytes) {
+        return Err(DecodeError::NonCanonicalNumber { offset });
+    }
+    String::from_utf8(number_bytes)
+        .ok()
+        .and_then(|number| number.parse::<i128>().ok())
+        .ok_or(DecodeError::InvalidNumber { offset })
 }
 
-fn decode_list<T: Read>(buf_stream: &mut BufferedStream<T>) -> Vec<Box<BType>> {
-    buf_stream.read_byte(); // skip the 'l'
+fn decode_list<T: Read>(
+    buf_stream: &mut BufferedStream<T>,
+    options: &DecodeOptions,
+    depth: usize,
+) -> Result<Vec<BType>, DecodeError> {
+    read_byte(buf_stream)?; // skip the 'l'
     let mut values = Vec::new();
-    while buf_stream.peek_byte().unwrap() != b'e' {
-        values.push(Box::new(decode(buf_stream)));
+    while peek_byte(buf_stream)? != b'e' {
+        values.push(decode_value(buf_stream, options, depth)?);
+    }
+    read_byte(buf_stream)?; // skip the trailing 'e'
+    Ok(values)
+}
+
+fn decode_dict<T: Read>(
+    buf_stream: &mut BufferedStream<T>,
+    options: &DecodeOptions,
+    depth: usize,
+) -> Result<BMap, DecodeError> {
+    read_byte(buf_stream)?; // skip the 'd'
+    let mut map = BMap::new();
+    let mut previous_key: Option<Vec<u8>> = None;
+    while peek_byte(buf_stream)? != b'e' {
+        let offset = buf_stream.position();
+        let key = decode_bytes(buf_stream, options)?;
+        if options.strict {
+            if let Some(previous_key) = &previous_key {
+                if key == *previous_key {
+                    return Err(DecodeError::DuplicateKey { offset });
+                }
+                if key < *previous_key {
+                    return Err(DecodeError::UnsortedKey { offset });
+                }
+            }
+            previous_key = Some(key.clone());
+        }
+        let value = decode_value(buf_stream, options, depth)?;
+        map.insert(key, value);
+    }
+    read_byte(buf_stream)?; // skip the trailing 'e'
+    Ok(map)
+}
+
+// decodes a single value straight out of a byte slice, borrowing byte strings from it instead of
+// copying them into a BType
+pub fn decode_slice<'a>(
+    bytes: &'a [u8],
+    options: &DecodeOptions,
+) -> Result<BValue<'a>, DecodeError> {
+    let mut reader = SliceReader { bytes, position: 0 };
+    let value = reader.decode_value(options, 0)?;
+    if options.strict && reader.position < bytes.len() {
+        return Err(DecodeError::TrailingData {
+            offset: reader.position,
+        });
+    }
+    Ok(value)
+}
+
+struct SliceReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> SliceReader<'a> {
+    fn decode_value(
+        &mut self,
+        options: &DecodeOptions,
+        depth: usize,
+    ) -> Result<BValue<'a>, DecodeError> {
+        let first_byte = self.peek_byte()?;
+        if first_byte.is_ascii_digit() {
+            Ok(BValue::Bytes(self.decode_bytes(options)?))
+        } else if first_byte == b'i' {
+            Ok(BValue::Number(self.decode_number(options)?))
+        } else if first_byte == b'l' {
+            check_depth(depth, self.position, options)?;
+            Ok(BValue::List(self.decode_list(options, depth + 1)?))
+        } else if first_byte == b'd' {
+            check_depth(depth, self.position, options)?;
+            Ok(BValue::Map(self.decode_dict(options, depth + 1)?))
+        } else {
+            Err(DecodeError::InvalidType {
+                byte: first_byte,
+                offset: self.position,
+            })
+        }
+    }
+
+    fn decode_bytes(&mut self, options: &DecodeOptions) -> Result<&'a [u8], DecodeError> {
+        let offset = self.position;
+        let length_bytes = self.read_until(b':', options)?;
+        if options.strict && !is_canonical_length(length_bytes) {
+            return Err(DecodeError::NonCanonicalLength { offset });
+        }
+        let length = std::str::from_utf8(length_bytes)
+            .ok()
+            .and_then(|length| length.parse::<usize>().ok())
+            .ok_or(DecodeError::InvalidLength { offset })?;
+        check_string(length, self.position, options)?;
+        self.read_n_bytes(length)
+    }
+
+    fn decode_number(&mut self, options: &DecodeOptions) -> Result<i128, DecodeError> {
+        self.position += 1; // skip the 'i'
+        let offset = self.position;
+        let number_bytes = self.read_until(b'e', options)?;
+        if options.strict && !is_canonical_number(number_bytes) {
+            return Err(DecodeError::NonCanonicalNumber { offset });
+        }
+        std::str::from_utf8(number_bytes)
+            .ok()
+            .and_then(|number| number.parse::<i128>().ok())
+            .ok_or(DecodeError::InvalidNumber { offset })
+    }
+
+    fn decode_list(
+        &mut self,
+        options: &DecodeOptions,
+        depth: usize,
+    ) -> Result<Vec<BValue<'a>>, DecodeError> {
+        self.position += 1; // skip the 'l'
+        let mut values = Vec::new();
+        while self.peek_byte()? != b'e' {
+            values.push(self.decode_value(options, depth)?);
+        }
+        self.position += 1; // skip the trailing 'e'
+        Ok(values)
     }
-    buf_stream.read_byte(); // skip the trailing 'e'
-    return values;
+
+    fn decode_dict(
+        &mut self,
+        options: &DecodeOptions,
+        depth: usize,
+    ) -> Result<BDict<'a>, DecodeError> {
+        let start = self.position;
+        self.position += 1; // skip the 'd'
+        let mut entries = BTreeMap::new();
+        let mut previous_key: Option<&[u8]> = None;
+        while self.peek_byte()? != b'e' {
+            let offset = self.position;
+            let key = self.decode_bytes(options)?;
+            if options.strict {
+                if let Some(previous_key) = previous_key {
+                    if key == previous_key {
+                        return Err(DecodeError::DuplicateKey { offset });
+                    }
+                    if key < previous_key {
+                        return Err(DecodeError::UnsortedKey { offset });
+                    }
+                }
+                previous_key = Some(key);
+            }
+            let value = self.decode_value(options, depth)?;
+            entries.insert(key, value);
+        }
+        self.position += 1; // skip the trailing 'e'
+        Ok(BDict::new(entries, &self.bytes[start..self.position]))
+    }
+
+    fn peek_byte(&self) -> Result<u8, DecodeError> {
+        self.bytes
+            .get(self.position)
+            .cloned()
+            .ok_or(DecodeError::UnexpectedEof {
+                offset: self.position,
+            })
+    }
+
+    fn read_until(
+        &mut self,
+        byte_match: u8,
+        options: &DecodeOptions,
+    ) -> Result<&'a [u8], DecodeError> {
+        let offset = self.position;
+        let length = self.bytes[offset..]
+            .iter()
+            .position(|byte| *byte == byte_match)
+            .ok_or(DecodeError::UnexpectedEof { offset })?;
+        check_size(offset + length + 1, offset, options)?;
+        self.position += length + 1;
+        Ok(&self.bytes[offset..offset + length])
+    }
+
+    fn read_n_bytes(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        let offset = self.position;
+        if self.bytes.len() - offset < n {
+            return Err(DecodeError::UnexpectedEof { offset });
+        }
+        self.position += n;
+        Ok(&self.bytes[offset..offset + n])
+    }
+}
+
+// in strict mode the top level value has to be the last thing in the stream
+fn check_end<T: Read>(
+    buf_stream: &mut BufferedStream<T>,
+    options: &DecodeOptions,
+) -> Result<(), DecodeError> {
+    let offset = buf_stream.position();
+    let next_byte = buf_stream
+        .peek_byte()
+        .map_err(|err| read_error(err, offset))?;
+    if options.strict && next_byte.is_some() {
+        return Err(DecodeError::TrailingData {
+            offset: buf_stream.position(),
+        });
+    }
+    Ok(())
+}
+
+fn check_depth(depth: usize, offset: usize, options: &DecodeOptions) -> Result<(), DecodeError> {
+    if depth >= options.max_depth {
+        return Err(DecodeError::TooDeep {
+            max_depth: options.max_depth,
+            offset,
+        });
+    }
+    Ok(())
+}
+
+// checks a string's length prefix before its bytes are read (and allocated)
+fn check_string(length: usize, offset: usize, options: &DecodeOptions) -> Result<(), DecodeError> {
+    if length > options.max_string_length {
+        return Err(DecodeError::StringTooLong {
+            length,
+            max_string_length: options.max_string_length,
+            offset,
+        });
+    }
+    check_size(offset.saturating_add(length), offset, options)
+}
+
+// end is the position the input would be read up to
+fn check_size(end: usize, offset: usize, options: &DecodeOptions) -> Result<(), DecodeError> {
+    if end > options.max_size {
+        return Err(DecodeError::TooLarge {
+            max_size: options.max_size,
+            offset,
+        });
+    }
+    Ok(())
+}
+
+// a run of digits without leading zeros
+fn is_canonical_length(digits: &[u8]) -> bool {
+    match digits {
+        [b'0'] => true,
+        [b'1'..=b'9', rest @ ..] => rest.iter().all(u8::is_ascii_digit),
+        _ => false,
+    }
+}
+
+// like a length, but also allowing a minus sign in front of anything but zero
+fn is_canonical_number(digits: &[u8]) -> bool {
+    match digits {
+        [b'-', rest @ ..] => rest.first() != Some(&b'0') && is_canonical_length(rest),
+        _ => is_canonical_length(digits),
+    }
+}
+
+fn read_error(err: io::Error, offset: usize) -> DecodeError {
+    match err.kind() {
+        ErrorKind::UnexpectedEof => DecodeError::UnexpectedEof { offset },
+        _ => DecodeError::Io {
+            offset,
+            source: err,
+        },
+    }
+}
+
+fn peek_byte<T: Read>(buf_stream: &mut BufferedStream<T>) -> Result<u8, DecodeError> {
+    let offset = buf_stream.position();
+    buf_stream
+        .peek_byte()
+        .map_err(|err| read_error(err, offset))?
+        .ok_or(DecodeError::UnexpectedEof { offset })
+}
+
+fn read_byte<T: Read>(buf_stream: &mut BufferedStream<T>) -> Result<u8, DecodeError> {
+    let offset = buf_stream.position();
+    buf_stream
+        .read_byte()
+        .map_err(|err| read_error(err, offset))
 }
 
-pub fn decode_map<T: Read>(buf_stream: &mut BufferedStream<T>) -> HashMap<String, Box<BType>> {
-    buf_stream.read_byte(); // skip the 'd'
-    let mut map = HashMap::new();
-    while buf_stream.peek_byte().unwrap() != b'e' {
-        let key = String::from_utf8(decode_bytes(buf_stream)).unwrap();
-        let value = decode(buf_stream);
-        map.insert(key, Box::new(value));
+// scans whatever is buffered for byte_match, a run of digits that never ends can't grow past
+// max_size
+fn read_until<T: Read>(
+    buf_stream: &mut BufferedStream<T>,
+    byte_match: u8,
+    options: &DecodeOptions,
+) -> Result<Vec<u8>, DecodeError> {
+    let offset = buf_stream.position();
+    let mut result = Vec::new();
+    loop {
+        let position = buf_stream.position();
+        let available = buf_stream
+            .fill_buf()
+            .map_err(|err| read_error(err, position))?;
+        if available.is_empty() {
+            return Err(DecodeError::UnexpectedEof { offset: position });
+        }
+        let found = available.iter().position(|byte| *byte == byte_match);
+        let length = found.map_or(available.len(), |index| index + 1);
+        check_size(position + length, offset, options)?;
+        result.extend_from_slice(&available[..found.unwrap_or(length)]);
+        buf_stream.consume(length);
+        if found.is_some() {
+            return Ok(result);
+        }
     }
-    buf_stream.read_byte(); // skip the trailing 'e'
-    return map;
 }
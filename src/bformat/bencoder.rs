@@ -1,44 +1,72 @@
-use std::collections::HashMap;
+use std::io::{self, Write};
 
-use super::btype::BType;
+use super::btype::{BMap, BType};
 
-pub fn encode(value: &BType) -> Vec<u8> {
+// writes the encoding straight into writer (a socket, file, hasher...) without building it in
+// memory first
+pub fn encode_to<W: Write + ?Sized>(value: &BType, writer: &mut W) -> io::Result<()> {
     match value {
-        BType::Bytes(bytes) => encode_bytes(bytes),
-        BType::Number(number) => encode_number(number),
-        BType::List(list) => encode_list(list),
-        BType::Map(map) => encode_map(map),
+        BType::Bytes(bytes) => encode_bytes(bytes, writer),
+        BType::Number(number) => encode_number(number, writer),
+        BType::List(list) => encode_list(list, writer),
+        BType::Map(map) => encode_map(map, writer),
     }
 }
 
-fn encode_bytes(bytes: &Vec<u8>) -> Vec<u8> {
-    let mut encoded_bytes: Vec<u8> = format!("{}:", bytes.len()).bytes().collect();
-    encoded_bytes.append(&mut bytes.clone());
-    encoded_bytes
+// the number of bytes encode_to will write for value, e.g. for length prefixing a message
+pub fn encoded_len(value: &BType) -> usize {
+    match value {
+        BType::Bytes(bytes) => bytes_len(bytes),
+        BType::Number(number) => number_len(*number) + 2,
+        BType::List(list) => list.iter().map(encoded_len).sum::<usize>() + 2,
+        BType::Map(map) => {
+            map.iter()
+                .map(|(key, value)| bytes_len(key) + encoded_len(value))
+                .sum::<usize>()
+                + 2
+        }
+    }
+}
+
+fn encode_bytes<W: Write + ?Sized>(bytes: &[u8], writer: &mut W) -> io::Result<()> {
+    write!(writer, "{}:", bytes.len())?;
+    writer.write_all(bytes)
 }
 
-fn encode_number(number: &i128) -> Vec<u8> {
-    format!("i{}e", number).bytes().collect()
+fn encode_number<W: Write + ?Sized>(number: &i128, writer: &mut W) -> io::Result<()> {
+    write!(writer, "i{}e", number)
 }
 
-fn encode_list(list: &Vec<Box<BType>>) -> Vec<u8> {
-    let mut encoded_bytes: Vec<u8> = vec![b'l'];
+fn encode_list<W: Write + ?Sized>(list: &[BType], writer: &mut W) -> io::Result<()> {
+    writer.write_all(b"l")?;
     for item in list {
-        encoded_bytes.append(&mut encode(item));
+        encode_to(item, writer)?;
     }
-    encoded_bytes.push(b'e');
-    encoded_bytes
-}
-
-fn encode_map(map: &HashMap<String, Box<BType>>) -> Vec<u8> {
-    let mut encoded_bytes: Vec<u8> = vec![b'd'];
-    let mut keys: Vec<&String> = map.keys().collect();
-    keys.sort_unstable();
-    for key in keys {
-        let value = map.get(key).unwrap();
-        encoded_bytes.append(&mut encode_bytes(&key.bytes().collect()));
-        encoded_bytes.append(&mut encode(value));
+    writer.write_all(b"e")
+}
+
+fn encode_map<W: Write + ?Sized>(map: &BMap, writer: &mut W) -> io::Result<()> {
+    writer.write_all(b"d")?;
+    // BMap iterates in raw byte order already
+    for (key, value) in map {
+        encode_bytes(key, writer)?;
+        encode_to(value, writer)?;
+    }
+    writer.write_all(b"e")
+}
+
+fn bytes_len(bytes: &[u8]) -> usize {
+    number_len(bytes.len() as i128) + 1 + bytes.len()
+}
+
+// number of characters in the decimal representation of number
+fn number_len(number: i128) -> usize {
+    let sign = if number < 0 { 1 } else { 0 };
+    let mut digits = 1;
+    let mut remaining = number.unsigned_abs();
+    while remaining >= 10 {
+        remaining /= 10;
+        digits += 1;
     }
-    encoded_bytes.push(b'e');
-    encoded_bytes
+    sign + digits
 }
@@ -1,24 +1,84 @@
-use std::collections::HashMap;
+use std::{
+    collections::{btree_map, BTreeMap},
+    fmt,
+};
+
+use thiserror::Error;
 
 #[derive(Debug)]
 pub enum BType {
     Bytes(Vec<u8>),
     Number(i128),
-    List(Vec<Box<BType>>),
-    Map(HashMap<String, Box<BType>>),
+    List(Vec<BType>),
+    Map(BMap),
+}
+
+// dictionary keys are raw byte strings, kept sorted by their raw bytes as the spec requires
+#[derive(Debug, Default)]
+pub struct BMap(BTreeMap<Vec<u8>, BType>);
+
+impl BMap {
+    pub fn new() -> BMap {
+        BMap(BTreeMap::new())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&BType> {
+        self.0.get(key.as_bytes())
+    }
+
+    pub fn get_raw(&self, key: &[u8]) -> Option<&BType> {
+        self.0.get(key)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.0.contains_key(key.as_bytes())
+    }
+
+    pub fn insert(&mut self, key: impl Into<Vec<u8>>, value: BType) -> Option<BType> {
+        self.0.insert(key.into(), value)
+    }
+
+    pub fn iter(&self) -> btree_map::Iter<'_, Vec<u8>, BType> {
+        self.0.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl<K: Into<Vec<u8>>, const N: usize> From<[(K, BType); N]> for BMap {
+    fn from(entries: [(K, BType); N]) -> BMap {
+        entries.into_iter().collect()
+    }
+}
+
+impl<K: Into<Vec<u8>>> FromIterator<(K, BType)> for BMap {
+    fn from_iter<I: IntoIterator<Item = (K, BType)>>(iter: I) -> BMap {
+        BMap(iter.into_iter().map(|(k, v)| (k.into(), v)).collect())
+    }
+}
+
+impl<'a> IntoIterator for &'a BMap {
+    type Item = (&'a Vec<u8>, &'a BType);
+    type IntoIter = btree_map::Iter<'a, Vec<u8>, BType>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
 }
 
 impl BType {
     pub fn to_json_value(&self) -> serde_json::Value {
         match self {
-            BType::Bytes(bytes) => {
-                let string_result = String::from_utf8(bytes.clone());
-                if string_result.is_ok() {
-                    serde_json::Value::String(string_result.unwrap())
-                } else {
-                    serde_json::Value::String(hex::encode(bytes))
-                }
-            }
+            BType::Bytes(bytes) => match String::from_utf8(bytes.clone()) {
+                Ok(string) => serde_json::Value::String(string),
+                Err(_) => serde_json::Value::String(hex::encode(bytes)),
+            },
             BType::Number(number) => {
                 serde_json::Value::Number(serde_json::Number::from_i128(*number).unwrap())
             }
@@ -32,42 +92,17 @@ impl BType {
             BType::Map(map) => {
                 let mut converted_map = serde_json::Map::new();
                 for (key, btype) in map {
-                    converted_map.insert(key.clone(), btype.to_json_value());
+                    let key = match String::from_utf8(key.clone()) {
+                        Ok(string) => string,
+                        Err(_) => hex::encode(key),
+                    };
+                    converted_map.insert(key, btype.to_json_value());
                 }
                 serde_json::Value::Object(converted_map)
             }
         }
     }
 
-    pub fn to_string(&self) -> String {
-        match self {
-            BType::Bytes(bytes) => String::from_utf8(bytes.clone()).unwrap(),
-            BType::Number(number) => format!("{}", number),
-            BType::List(list) => {
-                let mut string = "[".to_owned();
-                for item in list {
-                    string.push_str(item.to_string().as_str());
-                    string.push_str(",");
-                }
-                string.pop();
-                string.push_str("]");
-                string
-            }
-            BType::Map(map) => {
-                let mut string = "{".to_owned();
-                for (key, value) in map {
-                    string.push_str(key.as_str());
-                    string.push_str(":");
-                    string.push_str(value.to_string().as_str());
-                    string.push_str(",");
-                }
-                string.pop();
-                string.push_str("}");
-                string
-            }
-        }
-    }
-
     pub fn as_bytes(&self) -> Option<&Vec<u8>> {
         match self {
             BType::Bytes(bytes) => Some(bytes),
@@ -82,17 +117,198 @@ impl BType {
         }
     }
 
-    // pub fn as_list(&self) -> Option<&Vec<Box<BType>>> {
-    //     match self {
-    //         BType::List(list) => Some(list),
-    //         _ => None,
-    //     }
-    // }
+    pub fn as_list(&self) -> Option<&Vec<BType>> {
+        match self {
+            BType::List(list) => Some(list),
+            _ => None,
+        }
+    }
 
-    pub fn as_map(&self) -> Option<&HashMap<String, Box<BType>>> {
+    pub fn as_map(&self) -> Option<&BMap> {
         match self {
             BType::Map(map) => Some(map),
             _ => None,
         }
     }
 }
+
+// tags used by the lossless json form. dictionary keys starting with '$' get an extra '$' in
+// front, so a real key can never be mistaken for one of these
+const HEX_TAG: &str = "$hex";
+const INT_TAG: &str = "$int";
+const HEX_KEY_PREFIX: &str = "$hex:";
+
+#[derive(Debug, Error)]
+pub enum JsonError {
+    #[error("bencode can't represent {0}")]
+    Unsupported(&'static str),
+    #[error("invalid hex string {0:?}")]
+    InvalidHex(String),
+    #[error("invalid integer {0:?}")]
+    InvalidNumber(String),
+    #[error("unknown tag in {0:?}")]
+    UnknownTag(String),
+}
+
+impl BType {
+    // a json form that can be turned back into the exact same value with from_json_value: byte
+    // strings that aren't utf-8 become {"$hex": "..."}, integers json can't hold become
+    // {"$int": "..."} and non utf-8 dictionary keys become "$hex:..."
+    pub fn to_lossless_json_value(&self) -> serde_json::Value {
+        match self {
+            BType::Bytes(bytes) => match std::str::from_utf8(bytes) {
+                Ok(string) => serde_json::Value::String(string.to_owned()),
+                Err(_) => tagged(HEX_TAG, hex::encode(bytes)),
+            },
+            BType::Number(number) => match serde_json::Number::from_i128(*number) {
+                Some(number) => serde_json::Value::Number(number),
+                None => tagged(INT_TAG, number.to_string()),
+            },
+            BType::List(list) => {
+                serde_json::Value::Array(list.iter().map(BType::to_lossless_json_value).collect())
+            }
+            BType::Map(map) => serde_json::Value::Object(
+                map.iter()
+                    .map(|(key, value)| (json_key(key), value.to_lossless_json_value()))
+                    .collect(),
+            ),
+        }
+    }
+
+    // the inverse of to_lossless_json_value
+    pub fn from_json_value(value: &serde_json::Value) -> Result<BType, JsonError> {
+        match value {
+            serde_json::Value::Null => Err(JsonError::Unsupported("null")),
+            serde_json::Value::Bool(_) => Err(JsonError::Unsupported("booleans")),
+            serde_json::Value::Number(number) => number
+                .as_i64()
+                .map(i128::from)
+                .or_else(|| number.as_u64().map(i128::from))
+                .map(BType::Number)
+                .ok_or(JsonError::Unsupported("floating point numbers")),
+            serde_json::Value::String(string) => Ok(BType::Bytes(string.as_bytes().to_vec())),
+            serde_json::Value::Array(array) => Ok(BType::List(
+                array
+                    .iter()
+                    .map(BType::from_json_value)
+                    .collect::<Result<_, _>>()?,
+            )),
+            serde_json::Value::Object(object) => {
+                if let Some(value) = tag_value(object, HEX_TAG) {
+                    return Ok(BType::Bytes(decode_hex(value)?));
+                }
+                if let Some(value) = tag_value(object, INT_TAG) {
+                    return value
+                        .parse()
+                        .map(BType::Number)
+                        .map_err(|_| JsonError::InvalidNumber(value.to_owned()));
+                }
+                let mut map = BMap::new();
+                for (key, value) in object {
+                    map.insert(bencode_key(key)?, BType::from_json_value(value)?);
+                }
+                Ok(BType::Map(map))
+            }
+        }
+    }
+}
+
+fn tagged(tag: &str, value: String) -> serde_json::Value {
+    let mut object = serde_json::Map::new();
+    object.insert(tag.to_owned(), serde_json::Value::String(value));
+    serde_json::Value::Object(object)
+}
+
+// the string behind tag if object is exactly {tag: "..."}
+fn tag_value<'a>(
+    object: &'a serde_json::Map<String, serde_json::Value>,
+    tag: &str,
+) -> Option<&'a str> {
+    if object.len() != 1 {
+        return None;
+    }
+    object.get(tag).and_then(serde_json::Value::as_str)
+}
+
+fn json_key(key: &[u8]) -> String {
+    match std::str::from_utf8(key) {
+        Ok(key) if key.starts_with('$') => format!("${}", key),
+        Ok(key) => key.to_owned(),
+        Err(_) => format!("{}{}", HEX_KEY_PREFIX, hex::encode(key)),
+    }
+}
+
+fn bencode_key(key: &str) -> Result<Vec<u8>, JsonError> {
+    if let Some(escaped) = key.strip_prefix("$$") {
+        Ok(format!("${}", escaped).into_bytes())
+    } else if let Some(hex) = key.strip_prefix(HEX_KEY_PREFIX) {
+        decode_hex(hex)
+    } else if key.starts_with('$') {
+        Err(JsonError::UnknownTag(key.to_owned()))
+    } else {
+        Ok(key.as_bytes().to_vec())
+    }
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>, JsonError> {
+    hex::decode(hex).map_err(|_| JsonError::InvalidHex(hex.to_owned()))
+}
+
+// how many bytes of a binary string are shown before it's cut off
+const BINARY_PREVIEW_LENGTH: usize = 16;
+
+// an indented tree meant for people rather than programs, binary strings are shown as a short hex
+// preview with their length
+impl fmt::Display for BType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_tree(self, f, 0)
+    }
+}
+
+fn write_tree(value: &BType, f: &mut fmt::Formatter<'_>, indent: usize) -> fmt::Result {
+    match value {
+        BType::Bytes(bytes) => write_bytes(bytes, f),
+        BType::Number(number) => write!(f, "{}", number),
+        BType::List(list) if list.is_empty() => write!(f, "[]"),
+        BType::List(list) => {
+            writeln!(f, "[")?;
+            for item in list {
+                write!(f, "{:width$}", "", width = (indent + 1) * 2)?;
+                write_tree(item, f, indent + 1)?;
+                writeln!(f)?;
+            }
+            write!(f, "{:width$}]", "", width = indent * 2)
+        }
+        BType::Map(map) if map.is_empty() => write!(f, "{{}}"),
+        BType::Map(map) => {
+            writeln!(f, "{{")?;
+            for (key, value) in map {
+                write!(f, "{:width$}", "", width = (indent + 1) * 2)?;
+                match std::str::from_utf8(key) {
+                    Ok(key) => write!(f, "{}: ", key)?,
+                    Err(_) => write!(f, "<{}>: ", hex::encode(key))?,
+                }
+                write_tree(value, f, indent + 1)?;
+                writeln!(f)?;
+            }
+            write!(f, "{:width$}}}", "", width = indent * 2)
+        }
+    }
+}
+
+fn write_bytes(bytes: &[u8], f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if let Ok(string) = std::str::from_utf8(bytes) {
+        return write!(f, "{:?} ({} bytes)", string, bytes.len());
+    }
+    let preview = &bytes[..bytes.len().min(BINARY_PREVIEW_LENGTH)];
+    write!(
+        f,
+        "<binary, {} bytes> {}",
+        bytes.len(),
+        hex::encode(preview)
+    )?;
+    if preview.len() < bytes.len() {
+        write!(f, "...")?;
+    }
+    Ok(())
+}
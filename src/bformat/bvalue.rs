@@ -0,0 +1,45 @@
+use std::collections::{btree_map, BTreeMap};
+
+// a decoded value that borrows its byte strings from the buffer it was decoded from,
+// the zero-copy counterpart of BType
+#[derive(Debug)]
+pub enum BValue<'a> {
+    Bytes(&'a [u8]),
+    Number(i128),
+    List(Vec<BValue<'a>>),
+    Map(BDict<'a>),
+}
+
+#[derive(Debug)]
+pub struct BDict<'a> {
+    entries: BTreeMap<&'a [u8], BValue<'a>>,
+    // the dictionary exactly as it was encoded, including the surrounding 'd' and 'e'
+    raw: &'a [u8],
+}
+
+impl<'a> BDict<'a> {
+    pub fn new(entries: BTreeMap<&'a [u8], BValue<'a>>, raw: &'a [u8]) -> BDict<'a> {
+        BDict { entries, raw }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&BValue<'a>> {
+        self.entries.get(key.as_bytes())
+    }
+
+    pub fn raw(&self) -> &'a [u8] {
+        self.raw
+    }
+
+    pub fn iter(&self) -> btree_map::Iter<'_, &'a [u8], BValue<'a>> {
+        self.entries.iter()
+    }
+}
+
+impl<'a> BValue<'a> {
+    pub fn as_map(&self) -> Option<&BDict<'a>> {
+        match self {
+            BValue::Map(map) => Some(map),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,449 @@
+use std::{collections::btree_map, fmt, io::Read, iter, slice};
+
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, EnumAccess, MapAccess, SeqAccess, VariantAccess,
+    Visitor,
+};
+use thiserror::Error;
+
+use super::{
+    bdecoder::{self, DecodeError, DecodeOptions},
+    btype::BType,
+    bvalue::BValue,
+};
+use crate::buffered_stream::BufferedStream;
+
+#[derive(Debug, Error)]
+pub enum DeserializeError {
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+    #[error("missing field `{0}`")]
+    MissingField(&'static str),
+    #[error("invalid type: expected {expected}, found {found}")]
+    InvalidType { expected: String, found: String },
+    #[error("invalid value: expected {expected}, found {found}")]
+    InvalidValue { expected: String, found: String },
+    #[error("in field `{field}`: {source}")]
+    Field {
+        field: String,
+        source: Box<DeserializeError>,
+    },
+    #[error("{0}")]
+    Custom(String),
+}
+
+impl de::Error for DeserializeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        DeserializeError::Custom(msg.to_string())
+    }
+
+    fn missing_field(field: &'static str) -> Self {
+        DeserializeError::MissingField(field)
+    }
+
+    fn invalid_type(unexp: de::Unexpected, exp: &dyn de::Expected) -> Self {
+        DeserializeError::InvalidType {
+            expected: exp.to_string(),
+            found: unexp.to_string(),
+        }
+    }
+
+    fn invalid_value(unexp: de::Unexpected, exp: &dyn de::Expected) -> Self {
+        DeserializeError::InvalidValue {
+            expected: exp.to_string(),
+            found: unexp.to_string(),
+        }
+    }
+}
+
+pub fn from_btype<'a, D: de::Deserialize<'a>>(value: &'a BType) -> Result<D, DeserializeError> {
+    D::deserialize(NodeDeserializer(value))
+}
+
+// byte strings are borrowed from the buffer the value was decoded from, not from the value itself
+pub fn from_bvalue<'de, D: de::Deserialize<'de>>(
+    value: &BValue<'de>,
+) -> Result<D, DeserializeError> {
+    D::deserialize(NodeDeserializer(value))
+}
+
+// decodes a single value from the stream, leaving anything after it unread
+pub fn from_stream<T: Read, D: DeserializeOwned>(
+    buf_stream: &mut BufferedStream<T>,
+) -> Result<D, DeserializeError> {
+    let value = bdecoder::decode(buf_stream)?;
+    from_btype(&value)
+}
+
+// fields like &[u8] or &str borrow straight from bytes, without any copying
+pub fn from_slice<'de, D: de::Deserialize<'de>>(bytes: &'de [u8]) -> Result<D, DeserializeError> {
+    let value = bdecoder::decode_slice(bytes, &DecodeOptions::default())?;
+    from_bvalue(&value)
+}
+
+// the parts of a decoded tree the deserializer needs, so owned BTypes and borrowed BValues can share
+// one implementation
+trait Node<'de>: Copy {
+    type List: ExactSizeIterator<Item = Self>;
+    type Map: ExactSizeIterator<Item = (&'de [u8], Self)>;
+
+    fn view(self) -> View<'de, Self>;
+}
+
+enum View<'de, N: Node<'de>> {
+    Bytes(&'de [u8]),
+    Number(i128),
+    List(N::List),
+    Map(N::Map),
+}
+
+type BTypeEntry<'de> = (&'de Vec<u8>, &'de BType);
+
+impl<'de> Node<'de> for &'de BType {
+    type List = slice::Iter<'de, BType>;
+    type Map = iter::Map<
+        btree_map::Iter<'de, Vec<u8>, BType>,
+        fn(BTypeEntry<'de>) -> (&'de [u8], &'de BType),
+    >;
+
+    fn view(self) -> View<'de, Self> {
+        match self {
+            BType::Bytes(bytes) => View::Bytes(bytes),
+            BType::Number(number) => View::Number(*number),
+            BType::List(list) => View::List(list.iter()),
+            BType::Map(map) => View::Map(map.iter().map(btype_entry as _)),
+        }
+    }
+}
+
+fn btype_entry<'de>((key, value): BTypeEntry<'de>) -> (&'de [u8], &'de BType) {
+    (key, value)
+}
+
+type BValueEntry<'a, 'de> = (&'a &'de [u8], &'a BValue<'de>);
+
+fn bvalue_entry<'a, 'de>((key, value): BValueEntry<'a, 'de>) -> (&'de [u8], &'a BValue<'de>) {
+    (key, value)
+}
+
+impl<'a, 'de> Node<'de> for &'a BValue<'de> {
+    type List = slice::Iter<'a, BValue<'de>>;
+    type Map = iter::Map<
+        btree_map::Iter<'a, &'de [u8], BValue<'de>>,
+        fn(BValueEntry<'a, 'de>) -> (&'de [u8], &'a BValue<'de>),
+    >;
+
+    fn view(self) -> View<'de, Self> {
+        match self {
+            BValue::Bytes(bytes) => View::Bytes(bytes),
+            BValue::Number(number) => View::Number(*number),
+            BValue::List(list) => View::List(list.iter()),
+            BValue::Map(map) => View::Map(map.iter().map(bvalue_entry as _)),
+        }
+    }
+}
+
+fn unexpected<'de, N: Node<'de>>(value: N) -> de::Unexpected<'de> {
+    match value.view() {
+        View::Bytes(bytes) => match std::str::from_utf8(bytes) {
+            Ok(string) => de::Unexpected::Str(string),
+            Err(_) => de::Unexpected::Bytes(bytes),
+        },
+        View::Number(number) => match i64::try_from(number) {
+            Ok(number) => de::Unexpected::Signed(number),
+            Err(_) => de::Unexpected::Other("large integer"),
+        },
+        View::List(_) => de::Unexpected::Seq,
+        View::Map(_) => de::Unexpected::Map,
+    }
+}
+
+struct NodeDeserializer<N>(N);
+
+impl<'de, N: Node<'de>> de::Deserializer<'de> for NodeDeserializer<N> {
+    type Error = DeserializeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.0.view() {
+            View::Bytes(bytes) => match std::str::from_utf8(bytes) {
+                Ok(string) => visitor.visit_borrowed_str(string),
+                Err(_) => visitor.visit_borrowed_bytes(bytes),
+            },
+            View::Number(number) => match i64::try_from(number) {
+                Ok(number) => visitor.visit_i64(number),
+                Err(_) => visitor.visit_i128(number),
+            },
+            View::List(iter) => visitor.visit_seq(ListAccess::<N> { iter, index: 0 }),
+            View::Map(iter) => visitor.visit_map(DictAccess::<N> {
+                iter,
+                key: None,
+                value: None,
+            }),
+        }
+    }
+
+    // bencode has no booleans, they are conventionally sent as 0 or 1
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.0.view() {
+            View::Number(0) => visitor.visit_bool(false),
+            View::Number(1) => visitor.visit_bool(true),
+            _ => Err(de::Error::invalid_type(unexpected(self.0), &visitor)),
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.0.view() {
+            View::Bytes(bytes) => match std::str::from_utf8(bytes) {
+                Ok(string) => visitor.visit_borrowed_str(string),
+                Err(_) => Err(de::Error::invalid_value(unexpected(self.0), &visitor)),
+            },
+            _ => Err(de::Error::invalid_type(unexpected(self.0), &visitor)),
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.0.view() {
+            View::Bytes(bytes) => visitor.visit_borrowed_bytes(bytes),
+            _ => Err(de::Error::invalid_type(unexpected(self.0), &visitor)),
+        }
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_bytes(visitor)
+    }
+
+    // there is no null in bencode, so a value that is present is always Some
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    // unit variants are plain strings, all other variants are a dictionary with a single key
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        match self.0.view() {
+            View::Bytes(variant) => visitor.visit_enum(Enum::<N> {
+                variant: BytesKey(variant),
+                value: None,
+            }),
+            View::Map(mut iter) if iter.len() == 1 => {
+                let (variant, value) = iter.next().unwrap();
+                visitor.visit_enum(Enum {
+                    variant: BytesKey(variant),
+                    value: Some(value),
+                })
+            }
+            _ => Err(de::Error::invalid_type(unexpected(self.0), &visitor)),
+        }
+    }
+
+    // structs only ever come from dictionaries, a list in their place is a type error rather than
+    // something to fill the fields from in order
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        match self.0.view() {
+            View::Map(iter) => visitor.visit_map(DictAccess::<N> {
+                iter,
+                key: None,
+                value: None,
+            }),
+            _ => Err(de::Error::invalid_type(unexpected(self.0), &visitor)),
+        }
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_unit()
+    }
+
+    serde::forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char unit unit_struct seq tuple
+        tuple_struct map identifier
+    }
+}
+
+struct ListAccess<'de, N: Node<'de>> {
+    iter: N::List,
+    index: usize,
+}
+
+impl<'de, N: Node<'de>> SeqAccess<'de> for ListAccess<'de, N> {
+    type Error = DeserializeError;
+
+    fn next_element_seed<S: DeserializeSeed<'de>>(
+        &mut self,
+        seed: S,
+    ) -> Result<Option<S::Value>, Self::Error> {
+        match self.iter.next() {
+            Some(value) => {
+                let index = self.index;
+                self.index += 1;
+                seed.deserialize(NodeDeserializer(value))
+                    .map(Some)
+                    .map_err(|error| in_field(format!("[{index}]"), error))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+struct DictAccess<'de, N: Node<'de>> {
+    iter: N::Map,
+    key: Option<&'de [u8]>,
+    value: Option<N>,
+}
+
+impl<'de, N: Node<'de>> MapAccess<'de> for DictAccess<'de, N> {
+    type Error = DeserializeError;
+
+    fn next_key_seed<S: DeserializeSeed<'de>>(
+        &mut self,
+        seed: S,
+    ) -> Result<Option<S::Value>, Self::Error> {
+        match self.iter.next() {
+            Some((key, value)) => {
+                self.key = Some(key);
+                self.value = Some(value);
+                seed.deserialize(BytesKey(key)).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<S: DeserializeSeed<'de>>(
+        &mut self,
+        seed: S,
+    ) -> Result<S::Value, Self::Error> {
+        let value = self
+            .value
+            .take()
+            .ok_or_else(|| DeserializeError::Custom("value is missing".to_owned()))?;
+        let key = self.key.take().unwrap();
+        seed.deserialize(NodeDeserializer(value))
+            .map_err(|error| in_field(String::from_utf8_lossy(key).into_owned(), error))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+fn in_field(field: String, error: DeserializeError) -> DeserializeError {
+    DeserializeError::Field {
+        field,
+        source: Box::new(error),
+    }
+}
+
+// dictionary keys and enum variant names, which are raw byte strings
+struct BytesKey<'de>(&'de [u8]);
+
+impl<'de> de::Deserializer<'de> for BytesKey<'de> {
+    type Error = DeserializeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match std::str::from_utf8(self.0) {
+            Ok(string) => visitor.visit_borrowed_str(string),
+            Err(_) => visitor.visit_borrowed_bytes(self.0),
+        }
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_borrowed_bytes(self.0)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_borrowed_bytes(self.0)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string option unit
+        unit_struct newtype_struct seq tuple tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+struct Enum<'de, N> {
+    variant: BytesKey<'de>,
+    value: Option<N>,
+}
+
+impl<'de, N: Node<'de>> EnumAccess<'de> for Enum<'de, N> {
+    type Error = DeserializeError;
+    type Variant = Variant<N>;
+
+    fn variant_seed<S: DeserializeSeed<'de>>(
+        self,
+        seed: S,
+    ) -> Result<(S::Value, Self::Variant), Self::Error> {
+        let variant = seed.deserialize(self.variant)?;
+        Ok((variant, Variant { value: self.value }))
+    }
+}
+
+struct Variant<N> {
+    value: Option<N>,
+}
+
+impl<N> Variant<N> {
+    fn value(self) -> Result<NodeDeserializer<N>, DeserializeError> {
+        self.value
+            .map(NodeDeserializer)
+            .ok_or_else(|| de::Error::invalid_type(de::Unexpected::UnitVariant, &"variant value"))
+    }
+}
+
+impl<'de, N: Node<'de>> VariantAccess<'de> for Variant<N> {
+    type Error = DeserializeError;
+
+    fn unit_variant(self) -> Result<(), Self::Error> {
+        match self.value {
+            None => Ok(()),
+            Some(value) => Err(de::Error::invalid_type(unexpected(value), &"unit variant")),
+        }
+    }
+
+    fn newtype_variant_seed<S: DeserializeSeed<'de>>(
+        self,
+        seed: S,
+    ) -> Result<S::Value, Self::Error> {
+        seed.deserialize(self.value()?)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        de::Deserializer::deserialize_seq(self.value()?, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        de::Deserializer::deserialize_map(self.value()?, visitor)
+    }
+}
@@ -0,0 +1,136 @@
+use thiserror::Error;
+
+use super::btype::BType;
+
+#[derive(Debug, Error)]
+pub enum QueryError {
+    #[error("invalid path {path:?} at character {position}")]
+    InvalidPath { path: String, position: usize },
+    #[error("no key {key:?} at {at}")]
+    MissingKey { key: String, at: String },
+    #[error("index {index} out of range for list of {len} at {at}")]
+    IndexOutOfRange {
+        index: usize,
+        len: usize,
+        at: String,
+    },
+    #[error("expected a dictionary at {at}")]
+    NotADict { at: String },
+    #[error("expected a list at {at}")]
+    NotAList { at: String },
+}
+
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+// looks up a value by a path like `info.files[2].path`. keys that contain '.' or '[' can be written
+// quoted inside brackets, e.g. `info["piece length"]`, and an empty path is the value itself
+pub fn query<'a>(value: &'a BType, path: &str) -> Result<&'a BType, QueryError> {
+    let mut current = value;
+    // the part of the path resolved so far, for error messages
+    let mut at = String::new();
+    for segment in parse_path(path)? {
+        current = match segment {
+            Segment::Key(key) => {
+                let map = current
+                    .as_map()
+                    .ok_or(QueryError::NotADict { at: location(&at) })?;
+                let next = map.get(&key).ok_or(QueryError::MissingKey {
+                    key: key.clone(),
+                    at: location(&at),
+                })?;
+                if !at.is_empty() {
+                    at.push('.');
+                }
+                at.push_str(&key);
+                next
+            }
+            Segment::Index(index) => {
+                let list = current
+                    .as_list()
+                    .ok_or(QueryError::NotAList { at: location(&at) })?;
+                let next = list.get(index).ok_or(QueryError::IndexOutOfRange {
+                    index,
+                    len: list.len(),
+                    at: location(&at),
+                })?;
+                at.push_str(&format!("[{}]", index));
+                next
+            }
+        };
+    }
+    Ok(current)
+}
+
+fn location(at: &str) -> String {
+    if at.is_empty() {
+        "the top level".to_owned()
+    } else {
+        format!("`{}`", at)
+    }
+}
+
+fn parse_path(path: &str) -> Result<Vec<Segment>, QueryError> {
+    let invalid = |position: usize| QueryError::InvalidPath {
+        path: path.to_owned(),
+        position,
+    };
+    let chars: Vec<char> = path.chars().collect();
+    let mut segments = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '[' if chars.get(i + 1) == Some(&'"') => {
+                let start = i + 2;
+                let end = (start..chars.len())
+                    .find(|&j| chars[j] == '"')
+                    .ok_or(invalid(i))?;
+                if chars.get(end + 1) != Some(&']') {
+                    return Err(invalid(end + 1));
+                }
+                segments.push(Segment::Key(chars[start..end].iter().collect()));
+                i = end + 2;
+            }
+            '[' => {
+                let end = (i + 1..chars.len())
+                    .find(|&j| chars[j] == ']')
+                    .ok_or(invalid(i))?;
+                let index = chars[i + 1..end]
+                    .iter()
+                    .collect::<String>()
+                    .parse()
+                    .map_err(|_| invalid(i + 1))?;
+                segments.push(Segment::Index(index));
+                i = end + 1;
+            }
+            '.' if !segments.is_empty() => {
+                let start = i + 1;
+                let end = key_end(&chars, start);
+                if end == start {
+                    return Err(invalid(start));
+                }
+                segments.push(Segment::Key(chars[start..end].iter().collect()));
+                i = end;
+            }
+            _ if segments.is_empty() && i == 0 => {
+                let end = key_end(&chars, 0);
+                if end == 0 {
+                    return Err(invalid(0));
+                }
+                segments.push(Segment::Key(chars[..end].iter().collect()));
+                i = end;
+            }
+            _ => return Err(invalid(i)),
+        }
+    }
+    Ok(segments)
+}
+
+// a bare key runs until the next '.' or '['
+fn key_end(chars: &[char], start: usize) -> usize {
+    (start..chars.len())
+        .find(|&j| chars[j] == '.' || chars[j] == '[')
+        .unwrap_or(chars.len())
+}
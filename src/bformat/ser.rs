@@ -0,0 +1,368 @@
+use std::fmt;
+
+use serde::ser::{self, Serialize};
+use thiserror::Error;
+
+use super::btype::{BMap, BType};
+
+#[derive(Debug, Error)]
+pub enum SerializeError {
+    #[error("bencode can't represent {0}")]
+    Unsupported(&'static str),
+    #[error("dictionary keys must be strings or bytes")]
+    KeyMustBeBytes,
+    #[error("{0}")]
+    Custom(String),
+}
+
+impl ser::Error for SerializeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        SerializeError::Custom(msg.to_string())
+    }
+}
+
+pub fn to_btype<S: Serialize + ?Sized>(value: &S) -> Result<BType, SerializeError> {
+    value
+        .serialize(Serializer)?
+        .ok_or(SerializeError::Unsupported("a top level none"))
+}
+
+// serializes into Some(value), or None for values that should be left out entirely (a None
+// option), since bencode has no null
+struct Serializer;
+
+impl ser::Serializer for Serializer {
+    type Ok = Option<BType>;
+    type Error = SerializeError;
+    type SerializeSeq = SerializeList;
+    type SerializeTuple = SerializeList;
+    type SerializeTupleStruct = SerializeList;
+    type SerializeTupleVariant = SerializeVariant<SerializeList>;
+    type SerializeMap = SerializeDict;
+    type SerializeStruct = SerializeDict;
+    type SerializeStructVariant = SerializeVariant<SerializeDict>;
+
+    fn serialize_bool(self, v: bool) -> Result<Self::Ok, Self::Error> {
+        Ok(Some(BType::Number(v as i128)))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Self::Ok, Self::Error> {
+        Ok(Some(BType::Number(v.into())))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Self::Ok, Self::Error> {
+        Ok(Some(BType::Number(v.into())))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Self::Ok, Self::Error> {
+        Ok(Some(BType::Number(v.into())))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Self::Ok, Self::Error> {
+        Ok(Some(BType::Number(v.into())))
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<Self::Ok, Self::Error> {
+        Ok(Some(BType::Number(v)))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Self::Ok, Self::Error> {
+        Ok(Some(BType::Number(v.into())))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Self::Ok, Self::Error> {
+        Ok(Some(BType::Number(v.into())))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Self::Ok, Self::Error> {
+        Ok(Some(BType::Number(v.into())))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Self::Ok, Self::Error> {
+        Ok(Some(BType::Number(v.into())))
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<Self::Ok, Self::Error> {
+        i128::try_from(v)
+            .map(|v| Some(BType::Number(v)))
+            .map_err(|_| SerializeError::Unsupported("integers larger than i128"))
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<Self::Ok, Self::Error> {
+        Err(SerializeError::Unsupported("floating point numbers"))
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<Self::Ok, Self::Error> {
+        Err(SerializeError::Unsupported("floating point numbers"))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Self::Ok, Self::Error> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Self::Ok, Self::Error> {
+        Ok(Some(BType::Bytes(v.as_bytes().to_vec())))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok, Self::Error> {
+        Ok(Some(BType::Bytes(v.to_vec())))
+    }
+
+    fn serialize_none(self) -> Result<Self::Ok, Self::Error> {
+        Ok(None)
+    }
+
+    fn serialize_some<S: Serialize + ?Sized>(self, value: &S) -> Result<Self::Ok, Self::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Self::Ok, Self::Error> {
+        Err(SerializeError::Unsupported("unit values"))
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok, Self::Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Self::Ok, Self::Error> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<S: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &S,
+    ) -> Result<Self::Ok, Self::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<S: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &S,
+    ) -> Result<Self::Ok, Self::Error> {
+        let value = to_btype(value)?;
+        Ok(Some(BType::Map(BMap::from([(variant, value)]))))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+        Ok(SerializeList(Vec::with_capacity(len.unwrap_or(0))))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, Self::Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Self::Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Self::Error> {
+        Ok(SerializeVariant {
+            variant,
+            inner: SerializeList(Vec::with_capacity(len)),
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
+        Ok(SerializeDict {
+            map: BMap::new(),
+            key: None,
+        })
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStruct, Self::Error> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStructVariant, Self::Error> {
+        Ok(SerializeVariant {
+            variant,
+            inner: self.serialize_map(Some(len))?,
+        })
+    }
+}
+
+struct SerializeList(Vec<BType>);
+
+impl SerializeList {
+    fn push<S: Serialize + ?Sized>(&mut self, value: &S) -> Result<(), SerializeError> {
+        let value = value
+            .serialize(Serializer)?
+            .ok_or(SerializeError::Unsupported("none inside a list"))?;
+        self.0.push(value);
+        Ok(())
+    }
+}
+
+impl ser::SerializeSeq for SerializeList {
+    type Ok = Option<BType>;
+    type Error = SerializeError;
+
+    fn serialize_element<S: Serialize + ?Sized>(&mut self, value: &S) -> Result<(), Self::Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        Ok(Some(BType::List(self.0)))
+    }
+}
+
+impl ser::SerializeTuple for SerializeList {
+    type Ok = Option<BType>;
+    type Error = SerializeError;
+
+    fn serialize_element<S: Serialize + ?Sized>(&mut self, value: &S) -> Result<(), Self::Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeList {
+    type Ok = Option<BType>;
+    type Error = SerializeError;
+
+    fn serialize_field<S: Serialize + ?Sized>(&mut self, value: &S) -> Result<(), Self::Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+struct SerializeDict {
+    map: BMap,
+    key: Option<Vec<u8>>,
+}
+
+impl SerializeDict {
+    fn insert<S: Serialize + ?Sized>(
+        &mut self,
+        key: Vec<u8>,
+        value: &S,
+    ) -> Result<(), SerializeError> {
+        // None values are left out of the dictionary
+        if let Some(value) = value.serialize(Serializer)? {
+            self.map.insert(key, value);
+        }
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for SerializeDict {
+    type Ok = Option<BType>;
+    type Error = SerializeError;
+
+    fn serialize_key<S: Serialize + ?Sized>(&mut self, key: &S) -> Result<(), Self::Error> {
+        match key.serialize(Serializer)? {
+            Some(BType::Bytes(key)) => {
+                self.key = Some(key);
+                Ok(())
+            }
+            _ => Err(SerializeError::KeyMustBeBytes),
+        }
+    }
+
+    fn serialize_value<S: Serialize + ?Sized>(&mut self, value: &S) -> Result<(), Self::Error> {
+        let key = self.key.take().ok_or_else(|| {
+            SerializeError::Custom("serialize_value called before serialize_key".to_owned())
+        })?;
+        self.insert(key, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        Ok(Some(BType::Map(self.map)))
+    }
+}
+
+impl ser::SerializeStruct for SerializeDict {
+    type Ok = Option<BType>;
+    type Error = SerializeError;
+
+    fn serialize_field<S: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &S,
+    ) -> Result<(), Self::Error> {
+        self.insert(key.as_bytes().to_vec(), value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        ser::SerializeMap::end(self)
+    }
+}
+
+// variants other than unit variants are wrapped in a single entry dictionary keyed by their name
+struct SerializeVariant<T> {
+    variant: &'static str,
+    inner: T,
+}
+
+impl<T> SerializeVariant<T> {
+    fn wrap(variant: &'static str, value: Option<BType>) -> Option<BType> {
+        value.map(|value| BType::Map(BMap::from([(variant, value)])))
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeVariant<SerializeList> {
+    type Ok = Option<BType>;
+    type Error = SerializeError;
+
+    fn serialize_field<S: Serialize + ?Sized>(&mut self, value: &S) -> Result<(), Self::Error> {
+        self.inner.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        let value = ser::SerializeSeq::end(self.inner)?;
+        Ok(Self::wrap(self.variant, value))
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeVariant<SerializeDict> {
+    type Ok = Option<BType>;
+    type Error = SerializeError;
+
+    fn serialize_field<S: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &S,
+    ) -> Result<(), Self::Error> {
+        ser::SerializeStruct::serialize_field(&mut self.inner, key, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        let value = ser::SerializeMap::end(self.inner)?;
+        Ok(Self::wrap(self.variant, value))
+    }
+}
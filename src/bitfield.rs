@@ -0,0 +1,49 @@
+// which pieces someone has, kept in the wire format where the high bit of the first byte is
+// piece 0
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    pub fn new(len: usize) -> Bitfield {
+        Bitfield {
+            bytes: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    // replaces the contents with bytes as received in a bitfield message, any spare bits past
+    // the last piece are ignored
+    pub fn set_bytes(&mut self, bytes: &[u8]) {
+        self.bytes.fill(0);
+        let copied = bytes.len().min(self.bytes.len());
+        self.bytes[..copied].copy_from_slice(&bytes[..copied]);
+        let spare_bits = self.bytes.len() * 8 - self.len;
+        if let Some(last) = self.bytes.last_mut() {
+            *last &= 0xff << spare_bits;
+        }
+    }
+
+    // in the wire format, ready to go out in a bitfield message
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn has(&self, index: usize) -> bool {
+        index < self.len && self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn set(&mut self, index: usize) {
+        if index < self.len {
+            self.bytes[index / 8] |= 0x80 >> (index % 8);
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.bytes
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+}
@@ -1,67 +1,308 @@
-use std::{collections::VecDeque, io::Read};
+use std::{
+    io::{self, BufRead, ErrorKind, Read},
+    net::TcpStream,
+    time::Duration,
+};
+
+use bytes::{Buf, BytesMut};
+
+use crate::codec::Decoder;
+
+// how much is read from the underlying reader at once
+pub const CHUNK_SIZE: usize = 64 * 1024;
 
 pub struct BufferedStream<T: Read> {
     reader: T,
-    buffer: VecDeque<u8>,
+    buffer: ReadBuffer,
+    // number of bytes consumed from the stream so far
+    position: usize,
+    // how long a single read from reader may take before failing with ErrorKind::TimedOut
+    read_timeout: Option<Duration>,
 }
 
 impl<T: Read> BufferedStream<T> {
     pub fn new(reader: T) -> BufferedStream<T> {
         BufferedStream {
             reader,
-            buffer: VecDeque::new(),
+            buffer: ReadBuffer::new(),
+            position: 0,
+            read_timeout: None,
         }
     }
 
-    pub fn read_n_bytes(&mut self, n: usize) -> Option<Vec<u8>> {
-        if self.buffer.len() > n {
-            return Some(self.buffer.drain(..n).collect());
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    // fails with ErrorKind::UnexpectedEof if the stream ends before n bytes could be read
+    pub fn read_n_bytes(&mut self, n: usize) -> io::Result<Vec<u8>> {
+        let mut result = self.buffer.take(n);
+        let mut filled = result.len();
+        result.resize(n, 0);
+        while filled < n {
+            // reads that wouldn't fit the buffer anyway go straight into the result
+            let read = if n - filled >= CHUNK_SIZE {
+                read_from(&mut self.reader, self.read_timeout, &mut result[filled..])?
+            } else {
+                let available = self.fill_buf()?;
+                let read = available.len().min(n - filled);
+                result[filled..filled + read].copy_from_slice(&available[..read]);
+                self.buffer.consume(read);
+                read
+            };
+            if read == 0 {
+                return Err(short_read(filled, n));
+            }
+            filled += read;
+        }
+        self.position += n;
+        Ok(result)
+    }
+
+    pub fn read_byte(&mut self) -> io::Result<u8> {
+        let byte = self.peek_byte()?.ok_or_else(|| short_read(0, 1))?;
+        self.consume(1);
+        Ok(byte)
+    }
+
+    // None if the stream has ended cleanly, i.e. there is nothing left to read
+    pub fn peek_byte(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.fill_buf()?.first().cloned())
+    }
+
+    // Ok(None) once the stream has ended cleanly between two frames. peer connections run on
+    // tokio, so only tests read frames from a blocking stream for now
+    #[cfg_attr(not(test), expect(dead_code))]
+    pub fn read_frame<D: Decoder>(&mut self, codec: &mut D) -> Result<Option<D::Item>, D::Error> {
+        loop {
+            let unread = self.buffer.available().len();
+            if let Some(frame) = codec.decode(self.buffer.bytes_mut())? {
+                self.position += unread - self.buffer.available().len();
+                return Ok(Some(frame));
+            }
+            if self.read_more()? == 0 {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                return Err(truncated_frame(self.buffer.available().len()).into());
+            }
         }
-        let mut result: Vec<u8> = self.buffer.drain(..).collect();
-        result.append(
-            self.read_n_bytes_unbuffered(n - result.len())
-                .as_mut()
-                .unwrap(),
-        );
-        return Some(result);
     }
 
-    pub fn read_byte(&mut self) -> Option<u8> {
-        if self.buffer.len() == 0 {
-            return self.read_byte_unbuffered();
+    // appends whatever the reader has ready to the buffer, 0 meaning the stream has ended
+    fn read_more(&mut self) -> io::Result<usize> {
+        let spare = self.buffer.spare();
+        let result = read_from(&mut self.reader, self.read_timeout, spare);
+        // the spare room goes back on errors too, or it would pass for data on the next read
+        self.buffer.filled(*result.as_ref().unwrap_or(&0));
+        result
+    }
+}
+
+impl BufferedStream<TcpStream> {
+    // a read that times out leaves the stream as it was, so reading can carry on afterwards.
+    // peer connections run on tokio, so only tests make blocking ones for now
+    #[cfg_attr(not(test), expect(dead_code))]
+    pub fn set_read_timeout(&mut self, read_timeout: Option<Duration>) -> io::Result<()> {
+        self.reader.set_read_timeout(read_timeout)?;
+        self.read_timeout = read_timeout;
+        Ok(())
+    }
+}
+
+impl<T: Read> Read for BufferedStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+impl<T: Read> BufRead for BufferedStream<T> {
+    // only touches the underlying reader once everything buffered has been consumed
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.buffer.is_empty() {
+            self.read_more()?;
         }
-        return self.buffer.pop_front();
+        Ok(self.buffer.available())
     }
 
-    pub fn peek_byte(&mut self) -> Option<u8> {
-        if self.buffer.len() == 0 {
-            let byte = self.read_byte_unbuffered().unwrap();
-            self.buffer.push_back(byte);
+    fn consume(&mut self, amount: usize) {
+        self.buffer.consume(amount);
+        self.position += amount;
+    }
+}
+
+// the bytes read from the underlying reader but not consumed yet, shared with AsyncBufferedStream.
+// consumed bytes at the front are reclaimed when more room is needed, so the buffer doesn't grow
+// past what is actually unread
+pub struct ReadBuffer {
+    bytes: BytesMut,
+}
+
+impl ReadBuffer {
+    pub fn new() -> ReadBuffer {
+        ReadBuffer {
+            bytes: BytesMut::with_capacity(CHUNK_SIZE),
         }
-        return self.buffer.front().cloned();
     }
 
-    pub fn read_until(&mut self, byte_match: u8) -> Option<Vec<u8>> {
-        let mut result: Vec<u8> = Vec::new();
-        loop {
-            let byte = self.read_byte();
-            if byte.is_none() {
-                return None;
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn available(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn consume(&mut self, amount: usize) {
+        self.bytes.advance(amount.min(self.bytes.len()));
+    }
+
+    // removes up to n buffered bytes
+    pub fn take(&mut self, n: usize) -> Vec<u8> {
+        self.bytes.split_to(n.min(self.bytes.len())).to_vec()
+    }
+
+    // room for another chunk after the unread bytes, must be followed by filled with the number
+    // of bytes read into it
+    pub fn spare(&mut self) -> &mut [u8] {
+        let unread = self.bytes.len();
+        self.bytes.resize(unread + CHUNK_SIZE, 0);
+        &mut self.bytes[unread..]
+    }
+
+    pub fn filled(&mut self, read: usize) {
+        self.bytes.truncate(self.bytes.len() - CHUNK_SIZE + read);
+    }
+
+    // for reading straight into the buffer's spare capacity, e.g. with tokio's read_buf
+    pub fn bytes_mut(&mut self) -> &mut BytesMut {
+        self.bytes.reserve(CHUNK_SIZE);
+        &mut self.bytes
+    }
+}
+
+fn read_from(
+    reader: &mut impl Read,
+    read_timeout: Option<Duration>,
+    buf: &mut [u8],
+) -> io::Result<usize> {
+    loop {
+        match reader.read(buf) {
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            // sockets report an expired read timeout as WouldBlock on unix
+            Err(err) if err.kind() == ErrorKind::WouldBlock && read_timeout.is_some() => {
+                return Err(io::Error::new(ErrorKind::TimedOut, "read timed out"))
             }
-            if byte.unwrap() == byte_match {
-                return Some(result);
+            result => return result,
+        }
+    }
+}
+
+pub fn short_read(read: usize, wanted: usize) -> io::Error {
+    io::Error::new(
+        ErrorKind::UnexpectedEof,
+        format!("stream ended after {read} of {wanted} bytes"),
+    )
+}
+
+// for a stream that ends partway through a frame, leaving unread bytes of it behind
+pub fn truncated_frame(unread: usize) -> io::Error {
+    io::Error::new(
+        ErrorKind::UnexpectedEof,
+        format!("stream ended inside a frame, {unread} bytes left unread"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, net::TcpListener};
+
+    use super::*;
+    use crate::peer_message::{self, PeerCodec, PeerMessage, PeerMessageError};
+
+    // fails its first read with a recoverable error, then reads from data
+    struct FailsOnce<'a> {
+        failed: bool,
+        data: &'a [u8],
+    }
+
+    impl Read for FailsOnce<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if !self.failed {
+                self.failed = true;
+                return Err(ErrorKind::WouldBlock.into());
             }
-            result.push(byte.unwrap());
+            self.data.read(buf)
         }
     }
 
-    fn read_n_bytes_unbuffered(&mut self, n: usize) -> Option<Vec<u8>> {
-        let mut buf = vec![0u8; n];
-        self.reader.read_exact(&mut buf).unwrap();
-        return Some(buf);
+    #[test]
+    fn reads_peer_messages() {
+        let messages = [
+            PeerMessage::Unchoke,
+            PeerMessage::KeepAlive,
+            PeerMessage::Piece {
+                index: 1,
+                begin: 0x4000,
+                block: vec![7; CHUNK_SIZE + 100],
+            },
+        ];
+        let mut encoded = BytesMut::new();
+        for message in &messages {
+            peer_message::encode(message, &mut encoded);
+        }
+
+        let mut stream = BufferedStream::new(&encoded[..]);
+        let mut codec = PeerCodec::default();
+        for message in messages {
+            assert_eq!(stream.read_frame(&mut codec).unwrap(), Some(message));
+        }
+        assert_eq!(stream.read_frame(&mut codec).unwrap(), None);
+        assert_eq!(stream.position(), encoded.len());
+
+        // a frame cut off by the end of the stream isn't taken for a clean ending
+        let mut stream = BufferedStream::new(&encoded[..encoded.len() - 1]);
+        stream.read_frame(&mut codec).unwrap();
+        stream.read_frame(&mut codec).unwrap();
+        let err = stream.read_frame(&mut codec).unwrap_err();
+        assert!(matches!(err, PeerMessageError::Io(err) if err.kind() == ErrorKind::UnexpectedEof));
     }
 
-    fn read_byte_unbuffered(&mut self) -> Option<u8> {
-        return self.read_n_bytes_unbuffered(1).unwrap().get(0).cloned();
+    #[test]
+    fn carries_on_after_a_failed_read() {
+        let reader = FailsOnce {
+            failed: false,
+            data: b"hello",
+        };
+        let mut stream = BufferedStream::new(reader);
+        assert_eq!(
+            stream.read_byte().unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+        assert_eq!(stream.read_n_bytes(5).unwrap(), b"hello");
+        assert_eq!(stream.peek_byte().unwrap(), None);
+    }
+
+    #[test]
+    fn times_out_on_a_silent_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream =
+            BufferedStream::new(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+        let (mut peer, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+
+        assert_eq!(stream.read_byte().unwrap_err().kind(), ErrorKind::TimedOut);
+        peer.write_all(b"ab").unwrap();
+        assert_eq!(stream.read_n_bytes(2).unwrap(), b"ab");
+        // an ending is still told apart from a timeout
+        drop(peer);
+        assert_eq!(stream.peek_byte().unwrap(), None);
+        assert_eq!(stream.position(), 2);
     }
 }
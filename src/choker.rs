@@ -0,0 +1,317 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet},
+    time::{Duration, Instant},
+};
+
+// how often transfer rates are measured and the fastest peers picked again
+const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+// how long the optimistic unchoke stays with one peer
+const OPTIMISTIC_INTERVAL: Duration = Duration::from_secs(30);
+// a peer that sent us data before and then stopped for this long only gets the optimistic slot
+const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+// unchoked for their rates, on top of the one optimistic unchoke
+const REGULAR_SLOTS: usize = 3;
+
+struct ChokerPeer {
+    interested: bool,
+    unchoked: bool,
+    // bytes moved since the rates were last measured
+    uploaded: u64,
+    downloaded: u64,
+    // bytes per second over the last rechoke interval
+    upload_rate: u64,
+    download_rate: u64,
+    last_downloaded: Option<Instant>,
+}
+
+// the tit-for-tat choker as a plain state machine. the caller reports what its peers do along
+// with the current time and asks which peers to choke or unchoke, so it never looks at a clock or
+// a connection itself. peers are identified by whatever ids the caller gives them.
+// downloads don't serve peers yet, so the seed command is its only user and always runs it in
+// seeding mode. ranking by download rate and snubbing are there for when downloads do
+pub struct Choker {
+    peers: BTreeMap<usize, ChokerPeer>,
+    // while seeding nobody uploads to us, so peers are ranked by how fast they download from us
+    seeding: bool,
+    optimistic: Option<usize>,
+    last_optimistic: Option<Instant>,
+    last_rates: Instant,
+    // something changed that shouldn't wait for the next rechoke interval
+    dirty: bool,
+}
+
+impl Choker {
+    pub fn new(seeding: bool, now: Instant) -> Choker {
+        Choker {
+            peers: BTreeMap::new(),
+            seeding,
+            optimistic: None,
+            last_optimistic: None,
+            last_rates: now,
+            dirty: false,
+        }
+    }
+
+    // new peers start out choked
+    pub fn add_peer(&mut self, peer: usize) {
+        self.peers.insert(
+            peer,
+            ChokerPeer {
+                interested: false,
+                unchoked: false,
+                uploaded: 0,
+                downloaded: 0,
+                upload_rate: 0,
+                download_rate: 0,
+                last_downloaded: None,
+            },
+        );
+    }
+
+    pub fn remove_peer(&mut self, peer: usize) {
+        if let Some(removed) = self.peers.remove(&peer) {
+            // its slot can go to someone else straight away
+            self.dirty |= removed.unchoked;
+        }
+    }
+
+    pub fn set_interested(&mut self, peer: usize, interested: bool) {
+        if let Some(peer) = self.peers.get_mut(&peer) {
+            self.dirty |= peer.interested != interested;
+            peer.interested = interested;
+        }
+    }
+
+    // bytes of piece data sent to and received from peer
+    pub fn record_transfer(&mut self, peer: usize, uploaded: u64, downloaded: u64, now: Instant) {
+        if let Some(peer) = self.peers.get_mut(&peer) {
+            peer.uploaded += uploaded;
+            peer.downloaded += downloaded;
+            if downloaded > 0 {
+                peer.last_downloaded = Some(now);
+            }
+        }
+    }
+
+    // meant to be called every second or so and after reporting changes. returns (peer, choked)
+    // for every peer whose choke state should change
+    pub fn update(&mut self, now: Instant) -> Vec<(usize, bool)> {
+        let rates_due = now.duration_since(self.last_rates) >= RECHOKE_INTERVAL;
+        let optimistic_due = self
+            .last_optimistic
+            .is_none_or(|last| now.duration_since(last) >= OPTIMISTIC_INTERVAL);
+        if !rates_due && !optimistic_due && !self.dirty {
+            return Vec::new();
+        }
+        if rates_due {
+            self.measure_rates(now);
+        }
+        self.dirty = false;
+
+        // the regular slots go to the fastest interested peers that aren't snubbing us, ties go
+        // to whoever was added first
+        let mut candidates: Vec<usize> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.interested && !self.snubbed(peer, now))
+            .map(|(&id, _)| id)
+            .collect();
+        candidates.sort_by_key(|id| Reverse(self.rate(&self.peers[id])));
+        let mut unchoke: BTreeSet<usize> = candidates.into_iter().take(REGULAR_SLOTS).collect();
+
+        // the optimistic unchoke gives a peer without a good rate (yet) a chance to show what it
+        // can do, moving on once its time is up or it no longer needs the slot
+        let optimistic_valid = self.optimistic.is_some_and(|id| {
+            !unchoke.contains(&id) && self.peers.get(&id).is_some_and(|peer| peer.interested)
+        });
+        if optimistic_due || !optimistic_valid {
+            self.optimistic = self.next_optimistic(&unchoke);
+            self.last_optimistic = self.optimistic.map(|_| now);
+        }
+        unchoke.extend(self.optimistic);
+
+        let mut changes = Vec::new();
+        for (&id, peer) in self.peers.iter_mut() {
+            let unchoked = unchoke.contains(&id);
+            if peer.unchoked != unchoked {
+                peer.unchoked = unchoked;
+                changes.push((id, !unchoked));
+            }
+        }
+        changes
+    }
+
+    fn measure_rates(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_rates).as_secs_f64();
+        for peer in self.peers.values_mut() {
+            peer.upload_rate = (peer.uploaded as f64 / elapsed) as u64;
+            peer.download_rate = (peer.downloaded as f64 / elapsed) as u64;
+            peer.uploaded = 0;
+            peer.downloaded = 0;
+        }
+        self.last_rates = now;
+    }
+
+    fn rate(&self, peer: &ChokerPeer) -> u64 {
+        if self.seeding {
+            peer.upload_rate
+        } else {
+            peer.download_rate
+        }
+    }
+
+    fn snubbed(&self, peer: &ChokerPeer, now: Instant) -> bool {
+        !self.seeding
+            && peer
+                .last_downloaded
+                .is_some_and(|last| now.duration_since(last) >= SNUB_TIMEOUT)
+    }
+
+    // round robin over the interested peers that didn't get a regular slot, starting after the
+    // current optimistic unchoke
+    fn next_optimistic(&self, regular: &BTreeSet<usize>) -> Option<usize> {
+        let start = self.optimistic.map_or(0, |id| id + 1);
+        let after = self.peers.range(start..);
+        let before = self.peers.range(..start);
+        after
+            .chain(before)
+            .find(|(id, peer)| peer.interested && !regular.contains(id))
+            .map(|(&id, _)| id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn choker_with_peers(seeding: bool, now: Instant, count: usize) -> Choker {
+        let mut choker = Choker::new(seeding, now);
+        for peer in 0..count {
+            choker.add_peer(peer);
+            choker.set_interested(peer, true);
+        }
+        choker
+    }
+
+    // applies the changes update returns to the set of unchoked peers
+    fn update(choker: &mut Choker, now: Instant, unchoked: &mut BTreeSet<usize>) {
+        for (peer, choked) in choker.update(now) {
+            if choked {
+                assert!(unchoked.remove(&peer), "peer {peer} choked twice");
+            } else {
+                assert!(unchoked.insert(peer), "peer {peer} unchoked twice");
+            }
+        }
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn regular_slots_go_to_the_fastest_peers() {
+        let start = Instant::now();
+        let mut choker = choker_with_peers(false, start, 5);
+        let mut unchoked = BTreeSet::new();
+        update(&mut choker, start, &mut unchoked);
+        // three regular slots and the optimistic unchoke
+        assert_eq!(unchoked, BTreeSet::from([0, 1, 2, 3]));
+
+        choker.record_transfer(4, 0, 5000, start + secs(5));
+        choker.record_transfer(3, 0, 4000, start + secs(5));
+        choker.record_transfer(2, 0, 3000, start + secs(5));
+        choker.record_transfer(1, 0, 100, start + secs(5));
+        update(&mut choker, start + secs(10), &mut unchoked);
+        // 2, 3 and 4 are fastest, the optimistic unchoke moves on from 3 and wraps around to 0
+        assert_eq!(unchoked, BTreeSet::from([0, 2, 3, 4]));
+    }
+
+    #[test]
+    fn rates_are_only_measured_every_rechoke_interval() {
+        let start = Instant::now();
+        let mut choker = choker_with_peers(false, start, 5);
+        let mut unchoked = BTreeSet::new();
+        update(&mut choker, start, &mut unchoked);
+        assert_eq!(unchoked, BTreeSet::from([0, 1, 2, 3]));
+
+        choker.record_transfer(4, 0, 10000, start + secs(1));
+        assert!(choker.update(start + secs(5)).is_empty());
+        assert!(choker.update(start + secs(9)).is_empty());
+        update(&mut choker, start + secs(10), &mut unchoked);
+        assert_eq!(unchoked, BTreeSet::from([0, 1, 3, 4]));
+    }
+
+    #[test]
+    fn optimistic_unchoke_rotates_every_30_seconds() {
+        let start = Instant::now();
+        let mut choker = choker_with_peers(false, start, 5);
+        let mut unchoked = BTreeSet::new();
+        update(&mut choker, start, &mut unchoked);
+        assert_eq!(unchoked, BTreeSet::from([0, 1, 2, 3]));
+
+        update(&mut choker, start + secs(10), &mut unchoked);
+        update(&mut choker, start + secs(20), &mut unchoked);
+        assert_eq!(unchoked, BTreeSet::from([0, 1, 2, 3]));
+
+        update(&mut choker, start + secs(30), &mut unchoked);
+        assert_eq!(unchoked, BTreeSet::from([0, 1, 2, 4]));
+        // wraps around past the peers holding regular slots
+        update(&mut choker, start + secs(60), &mut unchoked);
+        assert_eq!(unchoked, BTreeSet::from([0, 1, 2, 3]));
+    }
+
+    #[test]
+    fn snubbing_peers_lose_their_regular_slot() {
+        let start = Instant::now();
+        let mut choker = choker_with_peers(false, start, 5);
+        let mut unchoked = BTreeSet::new();
+        choker.record_transfer(0, 0, 10000, start);
+        update(&mut choker, start, &mut unchoked);
+        update(&mut choker, start + secs(10), &mut unchoked);
+        assert!(unchoked.contains(&0));
+
+        // a minute without data from it, noticed at the next rechoke
+        update(&mut choker, start + secs(70), &mut unchoked);
+        assert_eq!(unchoked, BTreeSet::from([1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn seeding_ranks_peers_by_upload_rate() {
+        let start = Instant::now();
+        let mut choker = choker_with_peers(true, start, 5);
+        let mut unchoked = BTreeSet::new();
+        update(&mut choker, start, &mut unchoked);
+
+        choker.record_transfer(4, 9000, 500, start + secs(5));
+        choker.record_transfer(3, 8000, 0, start + secs(5));
+        choker.record_transfer(2, 7000, 0, start + secs(5));
+        // what a peer sends us doesn't count while seeding
+        choker.record_transfer(1, 0, 100000, start + secs(5));
+        update(&mut choker, start + secs(10), &mut unchoked);
+        assert_eq!(unchoked, BTreeSet::from([0, 2, 3, 4]));
+
+        // and never snubs anyone, 4 keeps its slot a minute after it last sent anything
+        choker.record_transfer(4, 9000, 0, start + secs(65));
+        choker.record_transfer(3, 8000, 0, start + secs(65));
+        choker.record_transfer(2, 7000, 0, start + secs(65));
+        update(&mut choker, start + secs(70), &mut unchoked);
+        assert!(unchoked.is_superset(&BTreeSet::from([2, 3, 4])));
+    }
+
+    #[test]
+    fn uninterested_peers_stay_choked() {
+        let start = Instant::now();
+        let mut choker = choker_with_peers(true, start, 3);
+        choker.add_peer(3);
+        let mut unchoked = BTreeSet::new();
+        update(&mut choker, start, &mut unchoked);
+        assert_eq!(unchoked, BTreeSet::from([0, 1, 2]));
+
+        // losing interest frees the slot straight away, without waiting for the next interval
+        choker.set_interested(1, false);
+        choker.set_interested(3, true);
+        update(&mut choker, start + secs(1), &mut unchoked);
+        assert_eq!(unchoked, BTreeSet::from([0, 2, 3]));
+    }
+}
@@ -0,0 +1,20 @@
+use std::io;
+
+use bytes::BytesMut;
+
+// the same shape as tokio_util's codec traits: a codec only ever sees a byte buffer, so it works
+// the same whether the bytes came from a blocking or an async stream
+
+pub trait Decoder {
+    type Item;
+    type Error: From<io::Error>;
+
+    // Ok(None) when src doesn't hold a complete frame yet, the frame is removed from src otherwise
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error>;
+}
+
+pub trait Encoder<Item> {
+    type Error: From<io::Error>;
+
+    fn encode(&mut self, item: Item, dst: &mut BytesMut) -> Result<(), Self::Error>;
+}
@@ -0,0 +1,611 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    sync::{Arc, Mutex},
+};
+
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{mpsc, Notify},
+    task::{self, JoinSet},
+};
+
+use crate::{
+    async_buffered_stream::AsyncBufferedStream,
+    bitfield::Bitfield,
+    peer_message::PeerMessage,
+    piece_picker::{Block, PiecePicker, Received},
+    storage::Storage,
+    torrent_info::TorrentInfo,
+    torrent_protocol::{self, PeerError},
+    tracker::TransferStats,
+};
+
+// how many peers are downloaded from at the same time
+const MAX_PEERS: usize = 30;
+// how many requests are kept outstanding with each peer
+const PIPELINE_DEPTH: usize = 5;
+
+#[derive(Debug, Error)]
+pub enum DownloadError {
+    #[error("ran out of peers with {missing} pieces left to download")]
+    OutOfPeers { missing: usize },
+    #[error("unable to write to disk: {0}")]
+    Storage(#[from] io::Error),
+}
+
+// downloads every piece that isn't in have from as many peers as possible at once, writing
+// pieces to storage as they come in. peers keep coming in over new_peers for as long as the
+// download runs, and what moves is counted in stats
+pub async fn download(
+    torrent_info: Arc<TorrentInfo>,
+    mut new_peers: mpsc::Receiver<Vec<String>>,
+    storage: &mut Storage,
+    have: &Bitfield,
+    stats: Arc<TransferStats>,
+) -> Result<(), DownloadError> {
+    let piece_count = torrent_info.piece_hashes.len();
+    let mut missing = piece_count - have.count();
+    if missing == 0 {
+        return Ok(());
+    }
+    let picker = PiecePicker::new(&torrent_info, have);
+    let work = Arc::new(WorkQueue::new(picker, stats.clone()));
+    let (results_sender, mut results) = mpsc::channel::<(usize, Vec<u8>)>(MAX_PEERS);
+
+    // dropping the set at the end aborts any peer still waiting for work
+    let mut peer_tasks = JoinSet::new();
+    // the peers there is a task for, so a peer the tracker hands out again isn't connected twice.
+    // keyed by task so a peer is let go of however its task ends, panics included
+    let mut connected: HashMap<task::Id, String> = HashMap::new();
+    // every peer there ever was a task for, to tell whether an announce brought anyone new
+    let mut tried: HashSet<String> = HashSet::new();
+    let mut more_peers = true;
+    // whether the latest announce only had peers that were tried before
+    let mut nobody_new = false;
+    while missing > 0 {
+        // once every peer task is gone and the tracker has no one else to offer, waiting for
+        // the next announce won't help
+        if peer_tasks.is_empty() && (nobody_new || !more_peers) && results.is_empty() {
+            return Err(DownloadError::OutOfPeers { missing });
+        }
+        tokio::select! {
+            biased;
+            Some((piece_index, piece)) = results.recv() => {
+                storage.write_piece(&torrent_info, piece_index, &piece)?;
+                stats.add_verified(torrent_info.piece_size(piece_index) as u64);
+                missing -= 1;
+            }
+            peers = new_peers.recv(), if more_peers => {
+                let Some(peers) = peers else {
+                    more_peers = false;
+                    continue;
+                };
+                // peers tried before get another chance, they may have been busy
+                nobody_new = peers.iter().all(|address| tried.contains(address));
+                for address in peers {
+                    if connected.len() == MAX_PEERS {
+                        break;
+                    }
+                    if connected.values().any(|connected| *connected == address) {
+                        continue;
+                    }
+                    tried.insert(address.clone());
+                    let torrent_info = torrent_info.clone();
+                    let work = work.clone();
+                    let results_sender = results_sender.clone();
+                    let task = peer_tasks.spawn({
+                        let address = address.clone();
+                        async move {
+                            let result =
+                                download_from_peer(&address, &torrent_info, &work, results_sender)
+                                    .await;
+                            if let Err(err) = result {
+                                eprintln!("peer {address}: {err}");
+                            }
+                        }
+                    });
+                    connected.insert(task.id(), address);
+                }
+            }
+            Some(finished) = peer_tasks.join_next_with_id() => {
+                // a later announce may bring the peer back
+                let id = match finished {
+                    Ok((id, ())) => id,
+                    Err(err) => err.id(),
+                };
+                connected.remove(&id);
+            }
+        }
+    }
+    work.finish();
+    storage.flush()?;
+    Ok(())
+}
+
+async fn download_from_peer(
+    address: &str,
+    torrent_info: &TorrentInfo,
+    work: &WorkQueue,
+    results: mpsc::Sender<(usize, Vec<u8>)>,
+) -> Result<(), PeerError> {
+    let (mut writer, mut reader) = torrent_protocol::connect(address).await?;
+    torrent_protocol::handshake(torrent_info, &mut writer, &mut reader, None).await?;
+    torrent_protocol::send_messages(&mut writer, &[PeerMessage::Interested]).await?;
+
+    let mut peer = PeerState {
+        id: work.join(),
+        bitfield: Bitfield::new(torrent_info.piece_hashes.len()),
+        pending: Vec::new(),
+        choked: true,
+    };
+    let result = exchange_blocks(
+        torrent_info,
+        work,
+        &results,
+        &mut writer,
+        &mut reader,
+        &mut peer,
+    )
+    .await;
+    // whatever the peer announced or was asked for goes back to the others
+    work.leave(&peer);
+    result
+}
+
+struct PeerState {
+    // tells the peer apart from the others in the work queue
+    id: usize,
+    bitfield: Bitfield,
+    // requests sent that haven't been answered yet
+    pending: Vec<Block>,
+    choked: bool,
+}
+
+async fn exchange_blocks<T: AsyncRead + Unpin>(
+    torrent_info: &TorrentInfo,
+    work: &WorkQueue,
+    results: &mpsc::Sender<(usize, Vec<u8>)>,
+    writer: &mut (impl AsyncWrite + Unpin),
+    reader: &mut AsyncBufferedStream<T>,
+    peer: &mut PeerState,
+) -> Result<(), PeerError> {
+    loop {
+        // registered before looking at the work so a change in between isn't missed
+        let changed = work.changed.notified();
+        let Some(requests) = work.requests(peer) else {
+            return Ok(());
+        };
+        torrent_protocol::send_messages(writer, &requests).await?;
+
+        let message = tokio::select! {
+            message = torrent_protocol::read_message(reader) => message?,
+            _ = changed => continue,
+        };
+        match message {
+            // the outstanding requests are dropped by a choke
+            PeerMessage::Choke => {
+                peer.choked = true;
+                work.cancel(&mut peer.pending);
+            }
+            PeerMessage::Unchoke => peer.choked = false,
+            PeerMessage::Bitfield(bytes) => work.set_bitfield(&mut peer.bitfield, &bytes),
+            PeerMessage::Have { index } => work.add_have(&mut peer.bitfield, index as usize),
+            PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                let received = Block {
+                    piece_index: index as usize,
+                    begin: begin as usize,
+                    length: block.len(),
+                };
+                // blocks cancelled in the meantime can still come in
+                let Some(position) = peer.pending.iter().position(|&b| b == received) else {
+                    continue;
+                };
+                peer.pending.remove(position);
+
+                let Received::Piece(piece) = work.received(peer.id, &received, block) else {
+                    continue;
+                };
+                let piece_index = received.piece_index;
+                if Sha1::digest(&piece).as_slice() != torrent_info.piece_hashes[piece_index] {
+                    // with blocks from several peers there's no telling which of them is at fault
+                    if work.piece_failed(peer.id, piece_index) {
+                        return Err(PeerError::HashMismatch(piece_index));
+                    }
+                    continue;
+                }
+                work.piece_verified(piece_index);
+                if results.send((piece_index, piece)).await.is_err() {
+                    return Ok(());
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// shared by every peer task
+struct WorkQueue {
+    state: Mutex<WorkState>,
+    // woken whenever requests are given back or dropped, a block other peers are also waiting
+    // for comes in, or the download finishes
+    changed: Notify,
+    stats: Arc<TransferStats>,
+}
+
+struct WorkState {
+    picker: PiecePicker,
+    // the peers that sent blocks of each piece that hasn't been hash checked yet
+    senders: HashMap<usize, HashSet<usize>>,
+    next_peer_id: usize,
+    done: bool,
+}
+
+impl WorkQueue {
+    fn new(picker: PiecePicker, stats: Arc<TransferStats>) -> WorkQueue {
+        WorkQueue {
+            state: Mutex::new(WorkState {
+                picker,
+                senders: HashMap::new(),
+                next_peer_id: 0,
+                done: false,
+            }),
+            changed: Notify::new(),
+            stats,
+        }
+    }
+
+    // an id for a new peer
+    fn join(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.next_peer_id += 1;
+        state.next_peer_id
+    }
+
+    // the messages to send to bring the peer up to date: cancels for requests that aren't needed
+    // anymore and new requests to keep its pipeline full. None once the download is done
+    fn requests(&self, peer: &mut PeerState) -> Option<Vec<PeerMessage>> {
+        let mut state = self.state.lock().unwrap();
+        if state.done {
+            return None;
+        }
+        let mut messages = Vec::new();
+        let picker = &mut state.picker;
+        peer.pending.retain(|block| {
+            let stale = picker.is_stale(block);
+            if stale {
+                picker.cancel(block);
+                messages.push(block_message(block, true));
+            }
+            !stale
+        });
+        if !peer.choked && peer.pending.len() < PIPELINE_DEPTH {
+            let count = PIPELINE_DEPTH - peer.pending.len();
+            let picked = picker.pick(&peer.bitfield, &peer.pending, count);
+            messages.extend(picked.iter().map(|block| block_message(block, false)));
+            peer.pending.extend(picked);
+        }
+        Some(messages)
+    }
+
+    fn set_bitfield(&self, bitfield: &mut Bitfield, bytes: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        state.picker.remove_peer(bitfield);
+        bitfield.set_bytes(bytes);
+        state.picker.add_peer(bitfield);
+    }
+
+    fn add_have(&self, bitfield: &mut Bitfield, piece_index: usize) {
+        if !bitfield.has(piece_index) {
+            bitfield.set(piece_index);
+            self.state.lock().unwrap().picker.add_have(piece_index);
+        }
+    }
+
+    fn received(&self, peer_id: usize, block: &Block, data: Vec<u8>) -> Received {
+        self.stats.add_downloaded(block.length as u64);
+        let mut state = self.state.lock().unwrap();
+        let (received, requested_elsewhere) = state.picker.received(block, data);
+        if !matches!(received, Received::Duplicate) {
+            state
+                .senders
+                .entry(block.piece_index)
+                .or_default()
+                .insert(peer_id);
+        }
+        drop(state);
+        // the other peers can cancel their requests for it
+        if requested_elsewhere {
+            self.changed.notify_waiters();
+        }
+        received
+    }
+
+    // gives requests that won't be answered back, so another peer can try
+    fn cancel(&self, pending: &mut Vec<Block>) {
+        let mut state = self.state.lock().unwrap();
+        for block in pending.drain(..) {
+            state.picker.cancel(&block);
+        }
+        self.changed.notify_waiters();
+    }
+
+    // puts the piece up for downloading again, returning whether peer_id sent all of it
+    fn piece_failed(&self, peer_id: usize, piece_index: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.picker.piece_failed(piece_index);
+        let senders = state.senders.remove(&piece_index).unwrap_or_default();
+        drop(state);
+        self.changed.notify_waiters();
+        senders.len() == 1 && senders.contains(&peer_id)
+    }
+
+    fn piece_verified(&self, piece_index: usize) {
+        self.state.lock().unwrap().senders.remove(&piece_index);
+    }
+
+    fn leave(&self, peer: &PeerState) {
+        let mut state = self.state.lock().unwrap();
+        state.picker.remove_peer(&peer.bitfield);
+        for block in &peer.pending {
+            state.picker.cancel(block);
+        }
+        self.changed.notify_waiters();
+    }
+
+    fn finish(&self) {
+        self.state.lock().unwrap().done = true;
+        self.changed.notify_waiters();
+    }
+}
+
+fn block_message(block: &Block, cancel: bool) -> PeerMessage {
+    let (index, begin, length) = (
+        block.piece_index as u32,
+        block.begin as u32,
+        block.length as u32,
+    );
+    if cancel {
+        PeerMessage::Cancel {
+            index,
+            begin,
+            length,
+        }
+    } else {
+        PeerMessage::Request {
+            index,
+            begin,
+            length,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::torrent_info::TorrentFile;
+
+    // two blocks per piece, the last piece short
+    const PIECE_LENGTH: usize = 0x8000;
+    const LENGTH: usize = 80000;
+
+    fn data() -> Vec<u8> {
+        (0..LENGTH).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    fn torrent_info(data: &[u8]) -> TorrentInfo {
+        TorrentInfo {
+            trackers: vec![vec!["http://tracker/announce".to_owned()]],
+            name: "data".to_owned(),
+            length: data.len(),
+            info_hash: vec![7; 20],
+            piece_length: PIECE_LENGTH,
+            piece_hashes: data
+                .chunks(PIECE_LENGTH)
+                .map(|piece| Sha1::digest(piece).to_vec())
+                .collect(),
+            files: vec![TorrentFile {
+                path: vec!["data".to_owned()],
+                length: data.len(),
+                offset: 0,
+            }],
+            multi_file: false,
+        }
+    }
+
+    fn full_bitfield(torrent_info: &TorrentInfo) -> Bitfield {
+        let mut bitfield = Bitfield::new(torrent_info.piece_hashes.len());
+        for piece_index in 0..torrent_info.piece_hashes.len() {
+            bitfield.set(piece_index);
+        }
+        bitfield
+    }
+
+    // a peer with every piece that unchokes whoever connects. a corrupt one flips a bit in
+    // every block it sends
+    async fn fake_peer(
+        torrent_info: Arc<TorrentInfo>,
+        data: Arc<Vec<u8>>,
+        corrupt: bool,
+    ) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (torrent_info, data) = (torrent_info.clone(), data.clone());
+                tokio::spawn(async move {
+                    let _ = serve(stream, &torrent_info, &data, corrupt).await;
+                });
+            }
+        });
+        address
+    }
+
+    async fn serve(
+        stream: tokio::net::TcpStream,
+        torrent_info: &TorrentInfo,
+        data: &[u8],
+        corrupt: bool,
+    ) -> Result<(), PeerError> {
+        let (mut writer, mut reader) = torrent_protocol::split(stream);
+        torrent_protocol::accept_handshake(torrent_info, &mut writer, &mut reader).await?;
+        let bitfield = full_bitfield(torrent_info).as_bytes().to_vec();
+        let messages = [PeerMessage::Bitfield(bitfield), PeerMessage::Unchoke];
+        torrent_protocol::send_messages(&mut writer, &messages).await?;
+        loop {
+            let PeerMessage::Request {
+                index,
+                begin,
+                length,
+            } = torrent_protocol::read_message(&mut reader).await?
+            else {
+                continue;
+            };
+            let start = index as usize * torrent_info.piece_length + begin as usize;
+            let mut block = data[start..start + length as usize].to_vec();
+            if corrupt {
+                block[0] ^= 1;
+            }
+            let piece = PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            };
+            torrent_protocol::send_messages(&mut writer, &[piece]).await?;
+        }
+    }
+
+    async fn run_download(
+        torrent_info: Arc<TorrentInfo>,
+        peers: Vec<String>,
+        output_path: &std::path::Path,
+    ) -> Result<(), DownloadError> {
+        let mut storage = Storage::open(&torrent_info, output_path.to_str().unwrap()).unwrap();
+        let have = Bitfield::new(torrent_info.piece_hashes.len());
+        let stats = Arc::new(TransferStats::new(torrent_info.length as u64));
+        // no announce comes after the first, so the download knows when it has run out of peers
+        let (peers_sender, new_peers) = mpsc::channel(1);
+        peers_sender.send(peers).await.unwrap();
+        drop(peers_sender);
+        download(torrent_info, new_peers, &mut storage, &have, stats).await
+    }
+
+    #[tokio::test]
+    async fn downloads_from_several_peers() {
+        let data = Arc::new(data());
+        let torrent_info = Arc::new(torrent_info(&data));
+        let mut peers = Vec::new();
+        for _ in 0..3 {
+            peers.push(fake_peer(torrent_info.clone(), data.clone(), false).await);
+        }
+        let directory = tempfile::tempdir().unwrap();
+        let output_path = directory.path().join("data");
+
+        run_download(torrent_info, peers, &output_path)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(output_path).unwrap(), *data);
+    }
+
+    #[tokio::test]
+    async fn drops_a_peer_that_sends_bad_pieces() {
+        let data = Arc::new(data());
+        let torrent_info = Arc::new(torrent_info(&data));
+        let peer = fake_peer(torrent_info.clone(), data.clone(), true).await;
+        let directory = tempfile::tempdir().unwrap();
+
+        let result = run_download(torrent_info, vec![peer], &directory.path().join("data")).await;
+        assert!(matches!(
+            result,
+            Err(DownloadError::OutOfPeers { missing: 3 })
+        ));
+    }
+
+    fn work_queue(torrent_info: &TorrentInfo) -> WorkQueue {
+        let have = Bitfield::new(torrent_info.piece_hashes.len());
+        let stats = Arc::new(TransferStats::new(torrent_info.length as u64));
+        WorkQueue::new(PiecePicker::new(torrent_info, &have), stats)
+    }
+
+    fn unchoked_peer(work: &WorkQueue, torrent_info: &TorrentInfo) -> PeerState {
+        PeerState {
+            id: work.join(),
+            bitfield: full_bitfield(torrent_info),
+            pending: Vec::new(),
+            choked: false,
+        }
+    }
+
+    #[test]
+    fn keeps_unchoked_peers_busy() {
+        let torrent_info = torrent_info(&data());
+        let work = work_queue(&torrent_info);
+        let mut peer = unchoked_peer(&work, &torrent_info);
+        peer.choked = true;
+        assert!(work.requests(&mut peer).unwrap().is_empty());
+
+        peer.choked = false;
+        let requests = work.requests(&mut peer).unwrap();
+        assert_eq!(requests.len(), PIPELINE_DEPTH);
+        assert_eq!(peer.pending.len(), PIPELINE_DEPTH);
+        // nothing more while the pipeline is full
+        assert!(work.requests(&mut peer).unwrap().is_empty());
+
+        work.finish();
+        assert!(work.requests(&mut peer).is_none());
+    }
+
+    #[test]
+    fn blames_a_peer_only_for_pieces_it_sent_alone() {
+        let torrent_info = torrent_info(&data());
+        let work = work_queue(&torrent_info);
+        let mut peer = unchoked_peer(&work, &torrent_info);
+        let other = unchoked_peer(&work, &torrent_info);
+        // a single pipeline asks for every block of the torrent
+        work.requests(&mut peer).unwrap();
+        let block = |piece_index, begin| Block {
+            piece_index,
+            begin,
+            length: 0x4000,
+        };
+
+        // the first piece comes in from both peers, the second only from the first
+        work.received(peer.id, &block(0, 0), vec![0; 0x4000]);
+        let received = work.received(other.id, &block(0, 0x4000), vec![0; 0x4000]);
+        assert!(matches!(received, Received::Piece(_)));
+        assert!(!work.piece_failed(other.id, 0));
+
+        work.received(peer.id, &block(1, 0), vec![0; 0x4000]);
+        let received = work.received(peer.id, &block(1, 0x4000), vec![0; 0x4000]);
+        assert!(matches!(received, Received::Piece(_)));
+        assert!(work.piece_failed(peer.id, 1));
+    }
+
+    #[test]
+    fn cancels_blocks_that_came_in_elsewhere() {
+        let torrent_info = torrent_info(&data());
+        let work = work_queue(&torrent_info);
+        let mut peer = unchoked_peer(&work, &torrent_info);
+        let other = unchoked_peer(&work, &torrent_info);
+        work.requests(&mut peer).unwrap();
+
+        let block = peer.pending[0];
+        work.received(other.id, &block, vec![0; block.length]);
+        let messages = work.requests(&mut peer).unwrap();
+        assert!(!peer.pending.contains(&block));
+        assert!(messages.iter().any(|message| matches!(
+            message,
+            PeerMessage::Cancel { index, begin, .. }
+                if *index as usize == block.piece_index && *begin as usize == block.begin
+        )));
+    }
+}
@@ -1,12 +1,33 @@
+mod async_buffered_stream;
 mod bformat;
+mod bitfield;
 mod buffered_stream;
+mod choker;
+mod codec;
+mod download;
+mod peer_message;
+mod piece_picker;
+mod rng;
+mod seed;
+mod storage;
 mod torrent_info;
 mod torrent_protocol;
-
-use bformat::bdecoder;
+mod tracker;
+mod udp_tracker;
+
+use bformat::{
+    bdecoder::{self, DecodeOptions},
+    bencoder,
+    btype::BType,
+    query,
+};
+use bitfield::Bitfield;
 use buffered_stream::BufferedStream;
-use std::{env, fs::File, io::Write, net::TcpStream};
+use std::{env, fs::File, io::Write, process, sync::Arc};
+use storage::Storage;
+use tokio::sync::mpsc;
 use torrent_info::TorrentInfo;
+use tracker::{AnnounceResponse, TrackerSession, TrackerTiers, TransferStats};
 
 #[tokio::main]
 async fn main() {
@@ -15,21 +36,55 @@ async fn main() {
 
     match command.as_str() {
         "decode" => {
-            let encoded_string = (&args[2]).clone();
+            let encoded_string = args[2].clone();
             let mut buf_stream = BufferedStream::new(encoded_string.as_bytes());
-            let decoded_value = bdecoder::decode(&mut buf_stream);
-            println!("{}", decoded_value.to_json_value().to_string());
+            let decoded_value = bdecoder::decode(&mut buf_stream).unwrap();
+            println!("{}", decoded_value.to_json_value());
+        }
+        "query" => {
+            let mut buf_stream = BufferedStream::new(File::open(&args[2]).unwrap());
+            let decoded_value = bdecoder::decode(&mut buf_stream).unwrap();
+            let path = args.get(3).map(String::as_str).unwrap_or("");
+            match query::query(&decoded_value, path) {
+                Ok(value) => println!("{value}"),
+                Err(err) => {
+                    println!("{err}");
+                    process::exit(1);
+                }
+            }
+        }
+        "to_json" => {
+            // unlike decode this keeps every byte, so encode can turn it back into the same file
+            let mut buf_stream = BufferedStream::new(File::open(&args[2]).unwrap());
+            let decoded_value = bdecoder::decode(&mut buf_stream).unwrap();
+            let json = serde_json::to_string_pretty(&decoded_value.to_lossless_json_value());
+            println!("{}", json.unwrap());
+        }
+        "encode" => {
+            let json: serde_json::Value =
+                serde_json::from_reader(File::open(&args[2]).unwrap()).unwrap();
+            let value = BType::from_json_value(&json).unwrap();
+            let mut file = File::create(&args[3]).unwrap();
+            bencoder::encode_to(&value, &mut file).unwrap();
         }
         "info" => {
-            let torrent_info = TorrentInfo::from_file(&args[2]);
+            let torrent_info = TorrentInfo::from_file(&args[2]).unwrap();
             print_torrent_info(&torrent_info);
         }
+        "validate" => {
+            // strict parsing refuses any torrent that isn't canonically encoded
+            match TorrentInfo::from_file_with_options(&args[2], &DecodeOptions::strict()) {
+                Ok(_) => println!("OK"),
+                Err(err) => {
+                    println!("Invalid torrent: {err}");
+                    process::exit(1);
+                }
+            }
+        }
         "peers" => {
-            let torrent_info = TorrentInfo::from_file(&args[2]);
+            let torrent_info = TorrentInfo::from_file(&args[2]).unwrap();
 
-            let response_btype = torrent_protocol::discovery(&torrent_info).await;
-            let response = response_btype.as_map().unwrap();
-            let peers = human_readable_peers(response.get("peers").unwrap().as_bytes().unwrap());
+            let peers = announce(&torrent_info).await.peer_addresses();
 
             let mut peers_string = String::new();
             for peer in peers {
@@ -38,57 +93,100 @@ async fn main() {
             peers_string.pop();
             println!("{}", peers_string);
         }
+        "scrape" => {
+            let torrent_info = if args[2].starts_with("magnet:") {
+                TorrentInfo::from_link(&args[2]).unwrap()
+            } else {
+                TorrentInfo::from_file(&args[2]).unwrap()
+            };
+            let info_hash = &torrent_info.info_hash;
+
+            let mut trackers = TrackerTiers::new(&torrent_info.trackers);
+            let (url, response) = trackers.scrape(&[info_hash]).await.unwrap();
+            println!("Tracker URL: {url}");
+            match response.files.get(info_hash) {
+                Some(stats) => println!(
+                    "Seeders: {}\nLeechers: {}\nCompleted: {}",
+                    stats.seeders, stats.leechers, stats.completed
+                ),
+                None => {
+                    println!("Tracker doesn't know the torrent");
+                    process::exit(1);
+                }
+            }
+        }
         "handshake" => {
-            let torrent_info = TorrentInfo::from_file(&args[2]);
-            let mut writer = TcpStream::connect(&args[3]).unwrap();
-            let mut reader = BufferedStream::new(writer.try_clone().unwrap());
+            let torrent_info = TorrentInfo::from_file(&args[2]).unwrap();
+            let (mut writer, mut reader) = torrent_protocol::connect(&args[3]).await.unwrap();
             let (peer_id, _) =
-                torrent_protocol::handshake(&torrent_info, &mut writer, &mut reader, None);
+                torrent_protocol::handshake(&torrent_info, &mut writer, &mut reader, None)
+                    .await
+                    .unwrap();
             println!("Peer ID: {}", hex::encode(peer_id));
         }
         "download_piece" => {
-            let torrent_info = TorrentInfo::from_file(&args[4]);
-            let response_btype = torrent_protocol::discovery(&torrent_info).await;
-            let response = response_btype.as_map().unwrap();
-            let peer = &human_readable_peers(response.get("peers").unwrap().as_bytes().unwrap())[0];
+            let torrent_info = TorrentInfo::from_file(&args[4]).unwrap();
+            let peer = &announce(&torrent_info).await.peer_addresses()[0];
 
-            let mut writer = TcpStream::connect(peer).unwrap();
-            let mut reader = BufferedStream::new(writer.try_clone().unwrap());
-            torrent_protocol::handshake(&torrent_info, &mut writer, &mut reader, None);
+            let (mut writer, mut reader) = torrent_protocol::connect(peer).await.unwrap();
+            torrent_protocol::handshake(&torrent_info, &mut writer, &mut reader, None)
+                .await
+                .unwrap();
 
-            torrent_protocol::send_interested(&mut writer, &mut reader);
+            torrent_protocol::send_interested(&mut writer, &mut reader)
+                .await
+                .unwrap();
 
             let data = torrent_protocol::download_piece(
                 &torrent_info,
-                (&args[5]).parse::<usize>().unwrap().to_owned(),
+                args[5].parse::<usize>().unwrap(),
                 &mut writer,
                 &mut reader,
             )
+            .await
             .unwrap();
             let mut file = File::create(&args[3]).unwrap();
             file.write_all(&data).unwrap();
             file.flush().unwrap();
         }
         "download" => {
-            let torrent_info = TorrentInfo::from_file(&args[4]);
-            let response_btype = torrent_protocol::discovery(&torrent_info).await;
-            let response = response_btype.as_map().unwrap();
-            let peer = &human_readable_peers(response.get("peers").unwrap().as_bytes().unwrap())[0];
-
-            let mut writer = TcpStream::connect(peer).unwrap();
-            let mut reader = BufferedStream::new(writer.try_clone().unwrap());
-            torrent_protocol::handshake(&torrent_info, &mut writer, &mut reader, None);
-
-            torrent_protocol::send_interested(&mut writer, &mut reader);
-
-            let mut file = File::create(&args[3]).unwrap();
-            for i in 0..torrent_info.piece_hashes.len() {
-                let data =
-                    torrent_protocol::download_piece(&torrent_info, i, &mut writer, &mut reader)
-                        .unwrap();
-                file.write_all(&data).unwrap();
+            let torrent_info = Arc::new(TorrentInfo::from_file(&args[4]).unwrap());
+            download_torrent(torrent_info, &args[3]).await;
+        }
+        "verify" => {
+            let torrent_info = TorrentInfo::from_file(&args[2]).unwrap();
+            let mut storage = Storage::open_read_only(&torrent_info, &args[3]).unwrap();
+            let valid = storage.verify(&torrent_info);
+            let piece_count = torrent_info.piece_hashes.len();
+            println!("Valid pieces: {}/{}", valid.count(), piece_count);
+            if valid.count() < piece_count {
+                println!(
+                    "Missing pieces: {}",
+                    missing_piece_ranges(&valid, piece_count)
+                );
+                process::exit(1);
             }
-            file.flush().unwrap();
+        }
+        "seed" => {
+            let torrent_info = Arc::new(TorrentInfo::from_file(&args[2]).unwrap());
+            let mut storage = Storage::open_read_only(&torrent_info, &args[3]).unwrap();
+            let have = storage.verify(&torrent_info);
+            println!(
+                "Seeding {}/{} pieces on port {}",
+                have.count(),
+                torrent_info.piece_hashes.len(),
+                torrent_protocol::LISTEN_PORT
+            );
+            let stats = Arc::new(TransferStats::new(missing_bytes(&torrent_info, &have)));
+            let session = TrackerSession::start(torrent_info.clone(), stats.clone(), None);
+            let port = torrent_protocol::LISTEN_PORT;
+            // seeding only ends with ctrl-c, or failing to listen on the port
+            let result = tokio::select! {
+                result = seed::seed(torrent_info, storage, have, port, stats) => result,
+                _ = tokio::signal::ctrl_c() => Ok(()),
+            };
+            session.stop().await;
+            result.unwrap();
         }
         "magnet_parse" => {
             let torrent_info_result = TorrentInfo::from_link(&args[2]);
@@ -99,8 +197,8 @@ async fn main() {
 
             println!(
                 "Tracker URL: {}\nInfo Hash: {}",
-                torrent_info.url,
-                hex::encode(torrent_info.info_hash)
+                torrent_info.tracker_url(),
+                hex::encode(&torrent_info.info_hash)
             );
         }
         "magnet_handshake" => {
@@ -110,22 +208,23 @@ async fn main() {
             }
             let torrent_info = torrent_info_result.unwrap();
 
-            let response_btype = torrent_protocol::discovery(&torrent_info).await;
-            let response = response_btype.as_map().unwrap();
-            let peer = &human_readable_peers(response.get("peers").unwrap().as_bytes().unwrap())[0];
+            let peer = &announce(&torrent_info).await.peer_addresses()[0];
 
-            let mut writer = TcpStream::connect(peer).unwrap();
-            let mut reader = BufferedStream::new(writer.try_clone().unwrap());
+            let (mut writer, mut reader) = torrent_protocol::connect(peer).await.unwrap();
             let (peer_id, reserved_bytes) = torrent_protocol::handshake(
                 &torrent_info,
                 &mut writer,
                 &mut reader,
                 Some([0, 0, 0, 0, 0, 0x10, 0, 0]),
-            );
+            )
+            .await
+            .unwrap();
             println!("Peer ID: {}", hex::encode(peer_id));
 
             if reserved_bytes[5] & 0x10 != 0 {
-                let metadata_id = torrent_protocol::extension_handshake(&mut writer, &mut reader);
+                let metadata_id = torrent_protocol::extension_handshake(&mut writer, &mut reader)
+                    .await
+                    .unwrap();
                 println!("Peer Metadata Extension ID: {metadata_id}");
             }
         }
@@ -136,30 +235,33 @@ async fn main() {
             }
             let partial_torrent_info = partial_torrent_info_result.unwrap();
 
-            let response_btype = torrent_protocol::discovery(&partial_torrent_info).await;
-            let response = response_btype.as_map().unwrap();
-            let peer = &human_readable_peers(response.get("peers").unwrap().as_bytes().unwrap())[0];
+            let peer = &announce(&partial_torrent_info).await.peer_addresses()[0];
 
-            let mut writer = TcpStream::connect(peer).unwrap();
-            let mut reader = BufferedStream::new(writer.try_clone().unwrap());
+            let (mut writer, mut reader) = torrent_protocol::connect(peer).await.unwrap();
             let (_, reserved_bytes) = torrent_protocol::handshake(
                 &partial_torrent_info,
                 &mut writer,
                 &mut reader,
                 Some([0, 0, 0, 0, 0, 0x10, 0, 0]),
-            );
+            )
+            .await
+            .unwrap();
 
             if reserved_bytes[5] & 0x10 == 0 {
                 panic!("expected to have a peer that supports extensions");
             }
 
-            let metadata_id = torrent_protocol::extension_handshake(&mut writer, &mut reader);
+            let metadata_id = torrent_protocol::extension_handshake(&mut writer, &mut reader)
+                .await
+                .unwrap();
             let torrent_info = torrent_protocol::request_metadata(
                 &partial_torrent_info,
                 metadata_id,
                 &mut writer,
                 &mut reader,
-            );
+            )
+            .await
+            .unwrap();
             print_torrent_info(&torrent_info);
         }
         "magnet_download_piece" => {
@@ -169,39 +271,45 @@ async fn main() {
             }
             let partial_torrent_info = partial_torrent_info_result.unwrap();
 
-            let response_btype = torrent_protocol::discovery(&partial_torrent_info).await;
-            let response = response_btype.as_map().unwrap();
-            let peer = &human_readable_peers(response.get("peers").unwrap().as_bytes().unwrap())[0];
+            let peer = &announce(&partial_torrent_info).await.peer_addresses()[0];
 
-            let mut writer = TcpStream::connect(peer).unwrap();
-            let mut reader = BufferedStream::new(writer.try_clone().unwrap());
+            let (mut writer, mut reader) = torrent_protocol::connect(peer).await.unwrap();
             let (_, reserved_bytes) = torrent_protocol::handshake(
                 &partial_torrent_info,
                 &mut writer,
                 &mut reader,
                 Some([0, 0, 0, 0, 0, 0x10, 0, 0]),
-            );
+            )
+            .await
+            .unwrap();
 
             if reserved_bytes[5] & 0x10 == 0 {
                 panic!("expected to have a peer that supports extensions");
             }
 
-            let metadata_id = torrent_protocol::extension_handshake(&mut writer, &mut reader);
+            let metadata_id = torrent_protocol::extension_handshake(&mut writer, &mut reader)
+                .await
+                .unwrap();
             let torrent_info = torrent_protocol::request_metadata(
                 &partial_torrent_info,
                 metadata_id,
                 &mut writer,
                 &mut reader,
-            );
+            )
+            .await
+            .unwrap();
 
-            torrent_protocol::send_interested(&mut writer, &mut reader);
+            torrent_protocol::send_interested(&mut writer, &mut reader)
+                .await
+                .unwrap();
 
             let data = torrent_protocol::download_piece(
                 &torrent_info,
-                (&args[5]).parse::<usize>().unwrap().to_owned(),
+                args[5].parse::<usize>().unwrap(),
                 &mut writer,
                 &mut reader,
             )
+            .await
             .unwrap();
             let mut file = File::create(&args[3]).unwrap();
             file.write_all(&data).unwrap();
@@ -214,41 +322,36 @@ async fn main() {
             }
             let partial_torrent_info = partial_torrent_info_result.unwrap();
 
-            let response_btype = torrent_protocol::discovery(&partial_torrent_info).await;
-            let response = response_btype.as_map().unwrap();
-            let peer = &human_readable_peers(response.get("peers").unwrap().as_bytes().unwrap())[0];
+            // the tracker session only starts once the metadata is in, a peer is needed first
+            let peer = &announce(&partial_torrent_info).await.peer_addresses()[0];
 
-            let mut writer = TcpStream::connect(peer).unwrap();
-            let mut reader = BufferedStream::new(writer.try_clone().unwrap());
+            let (mut writer, mut reader) = torrent_protocol::connect(peer).await.unwrap();
             let (_, reserved_bytes) = torrent_protocol::handshake(
                 &partial_torrent_info,
                 &mut writer,
                 &mut reader,
                 Some([0, 0, 0, 0, 0, 0x10, 0, 0]),
-            );
+            )
+            .await
+            .unwrap();
 
             if reserved_bytes[5] & 0x10 == 0 {
                 panic!("expected to have a peer that supports extensions");
             }
 
-            let metadata_id = torrent_protocol::extension_handshake(&mut writer, &mut reader);
+            let metadata_id = torrent_protocol::extension_handshake(&mut writer, &mut reader)
+                .await
+                .unwrap();
             let torrent_info = torrent_protocol::request_metadata(
                 &partial_torrent_info,
                 metadata_id,
                 &mut writer,
                 &mut reader,
-            );
-
-            torrent_protocol::send_interested(&mut writer, &mut reader);
+            )
+            .await
+            .unwrap();
 
-            let mut file = File::create(&args[3]).unwrap();
-            for i in 0..torrent_info.piece_hashes.len() {
-                let data =
-                    torrent_protocol::download_piece(&torrent_info, i, &mut writer, &mut reader)
-                        .unwrap();
-                file.write_all(&data).unwrap();
-            }
-            file.flush().unwrap();
+            download_torrent(Arc::new(torrent_info), &args[3]).await;
         }
         _ => {
             println!("unknown command: {}", args[1])
@@ -259,7 +362,7 @@ async fn main() {
 fn print_torrent_info(torrent_info: &TorrentInfo) {
     let mut info_string = format!(
         "Tracker URL: {}\nLength: {}\nInfo Hash: {}\nPiece Length: {}\nPiece Hashes:",
-        torrent_info.url,
+        torrent_info.tracker_url(),
         torrent_info.length,
         hex::encode(&torrent_info.info_hash),
         torrent_info.piece_length,
@@ -267,23 +370,83 @@ fn print_torrent_info(torrent_info: &TorrentInfo) {
     for hash in &torrent_info.piece_hashes {
         info_string.push_str(format!("\n{}", hex::encode(hash)).as_str());
     }
+    if torrent_info.trackers.iter().flatten().count() > 1 {
+        info_string.push_str("\nTracker Tiers:");
+        for (tier_index, tier) in torrent_info.trackers.iter().enumerate() {
+            info_string.push_str(format!("\n{}: {}", tier_index, tier.join(", ")).as_str());
+        }
+    }
+    if torrent_info.multi_file {
+        info_string.push_str(format!("\nName: {}\nFiles:", torrent_info.name).as_str());
+        for file in &torrent_info.files {
+            info_string.push_str(format!("\n{} ({})", file.path.join("/"), file.length).as_str());
+        }
+    }
     println!("{info_string}");
 }
 
-fn human_readable_peers(peer_bytes: &Vec<u8>) -> Vec<String> {
-    let mut peer_strings = Vec::new();
-    let mut start = 0;
-    while start < peer_bytes.len() {
-        let peer_slice = &peer_bytes[start..start + 6];
-        peer_strings.push(format!(
-            "{}.{}.{}.{}:{}",
-            peer_slice[0],
-            peer_slice[1],
-            peer_slice[2],
-            peer_slice[3],
-            ((peer_slice[4] as u16) << 8) + peer_slice[5] as u16
-        ));
-        start += 6;
+// e.g. "0-3, 7, 9-10"
+fn missing_piece_ranges(valid: &Bitfield, piece_count: usize) -> String {
+    let mut ranges = Vec::new();
+    let mut piece_index = 0;
+    while piece_index < piece_count {
+        if valid.has(piece_index) {
+            piece_index += 1;
+            continue;
+        }
+        let start = piece_index;
+        while piece_index < piece_count && !valid.has(piece_index) {
+            piece_index += 1;
+        }
+        if piece_index - start == 1 {
+            ranges.push(format!("{start}"));
+        } else {
+            ranges.push(format!("{}-{}", start, piece_index - 1));
+        }
     }
-    peer_strings
+    ranges.join(", ")
+}
+
+// asks the trackers for peers, passing on any warning they have
+async fn announce(torrent_info: &TorrentInfo) -> AnnounceResponse {
+    let response = torrent_protocol::discovery(torrent_info).await.unwrap();
+    if let Some(warning) = &response.warning_message {
+        eprintln!("tracker warning: {warning}");
+    }
+    response
+}
+
+// downloads whatever output_path is missing with peers from a tracker session, until done or
+// ctrl-c is pressed
+async fn download_torrent(torrent_info: Arc<TorrentInfo>, output_path: &str) {
+    // pieces left on disk by an earlier run are kept and not downloaded again
+    let mut storage = Storage::open(&torrent_info, output_path).unwrap();
+    let have = storage.verify(&torrent_info);
+    let missing = missing_bytes(&torrent_info, &have);
+    let stats = Arc::new(TransferStats::new(missing));
+    let (peers_sender, peers) = mpsc::channel(1);
+    let session = TrackerSession::start(torrent_info.clone(), stats.clone(), Some(peers_sender));
+
+    let result = tokio::select! {
+        result = download::download(torrent_info, peers, &mut storage, &have, stats) => Some(result),
+        _ = tokio::signal::ctrl_c() => None,
+    };
+    match &result {
+        // a torrent that was complete to begin with wasn't completed by us
+        Some(Ok(())) if missing > 0 => session.completed(),
+        Some(_) => {}
+        // whatever made it to disk is picked up again by the next run
+        None => storage.flush().unwrap(),
+    }
+    session.stop().await;
+    if let Some(result) = result {
+        result.unwrap();
+    }
+}
+
+fn missing_bytes(torrent_info: &TorrentInfo, have: &Bitfield) -> u64 {
+    (0..torrent_info.piece_hashes.len())
+        .filter(|&piece_index| !have.has(piece_index))
+        .map(|piece_index| torrent_info.piece_size(piece_index) as u64)
+        .sum()
 }
@@ -0,0 +1,331 @@
+use std::io;
+
+use bytes::{Buf, BufMut, BytesMut};
+use thiserror::Error;
+
+use crate::codec::{Decoder, Encoder};
+
+// the largest message we accept, comfortably above a 16KiB block or metadata piece and the
+// bitfield of any realistic torrent
+const MAX_MESSAGE_LENGTH: usize = 1 << 20;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PeerMessage {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have {
+        index: u32,
+    },
+    Bitfield(Vec<u8>),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Port(u16),
+    // BEP 10, id 0 is the extension handshake and anything else an id agreed on during it
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Error)]
+pub enum PeerMessageError {
+    #[error("unknown message id {0}")]
+    UnknownId(u8),
+    #[error("message with id {id} can't be {length} bytes long")]
+    InvalidLength { id: u8, length: usize },
+    #[error("message of {length} bytes is larger than {max_length}")]
+    TooLarge { length: usize, max_length: usize },
+    #[error("failed to read message: {0}")]
+    Io(#[from] io::Error),
+}
+
+// writes message including its length prefix
+pub fn encode(message: &PeerMessage, dst: &mut BytesMut) {
+    let (id, payload_length) = match message {
+        PeerMessage::KeepAlive => {
+            dst.put_u32(0);
+            return;
+        }
+        PeerMessage::Choke => (0, 0),
+        PeerMessage::Unchoke => (1, 0),
+        PeerMessage::Interested => (2, 0),
+        PeerMessage::NotInterested => (3, 0),
+        PeerMessage::Have { .. } => (4, 4),
+        PeerMessage::Bitfield(bitfield) => (5, bitfield.len()),
+        PeerMessage::Request { .. } => (6, 12),
+        PeerMessage::Piece { block, .. } => (7, 8 + block.len()),
+        PeerMessage::Cancel { .. } => (8, 12),
+        PeerMessage::Port(_) => (9, 2),
+        PeerMessage::Extended { payload, .. } => (20, 1 + payload.len()),
+    };
+    dst.reserve(5 + payload_length);
+    dst.put_u32(1 + payload_length as u32);
+    dst.put_u8(id);
+    match message {
+        PeerMessage::Have { index } => dst.put_u32(*index),
+        PeerMessage::Bitfield(bitfield) => dst.put_slice(bitfield),
+        PeerMessage::Request {
+            index,
+            begin,
+            length,
+        }
+        | PeerMessage::Cancel {
+            index,
+            begin,
+            length,
+        } => {
+            dst.put_u32(*index);
+            dst.put_u32(*begin);
+            dst.put_u32(*length);
+        }
+        PeerMessage::Piece {
+            index,
+            begin,
+            block,
+        } => {
+            dst.put_u32(*index);
+            dst.put_u32(*begin);
+            dst.put_slice(block);
+        }
+        PeerMessage::Port(port) => dst.put_u16(*port),
+        PeerMessage::Extended { id, payload } => {
+            dst.put_u8(*id);
+            dst.put_slice(payload);
+        }
+        _ => {}
+    }
+}
+
+// decodes a message without its length prefix, an empty one being a keep-alive
+pub fn decode(mut message: &[u8]) -> Result<PeerMessage, PeerMessageError> {
+    if message.is_empty() {
+        return Ok(PeerMessage::KeepAlive);
+    }
+    let length = message.len();
+    let id = message.get_u8();
+    let expect_length = |expected: usize| {
+        if length == expected {
+            Ok(())
+        } else {
+            Err(PeerMessageError::InvalidLength { id, length })
+        }
+    };
+    let message = match id {
+        0 => expect_length(1).map(|_| PeerMessage::Choke)?,
+        1 => expect_length(1).map(|_| PeerMessage::Unchoke)?,
+        2 => expect_length(1).map(|_| PeerMessage::Interested)?,
+        3 => expect_length(1).map(|_| PeerMessage::NotInterested)?,
+        4 => {
+            expect_length(5)?;
+            PeerMessage::Have {
+                index: message.get_u32(),
+            }
+        }
+        5 => PeerMessage::Bitfield(message.to_vec()),
+        6 | 8 => {
+            expect_length(13)?;
+            let (index, begin, length) = (message.get_u32(), message.get_u32(), message.get_u32());
+            if id == 6 {
+                PeerMessage::Request {
+                    index,
+                    begin,
+                    length,
+                }
+            } else {
+                PeerMessage::Cancel {
+                    index,
+                    begin,
+                    length,
+                }
+            }
+        }
+        7 => {
+            if length < 9 {
+                return Err(PeerMessageError::InvalidLength { id, length });
+            }
+            PeerMessage::Piece {
+                index: message.get_u32(),
+                begin: message.get_u32(),
+                block: message.to_vec(),
+            }
+        }
+        9 => {
+            expect_length(3)?;
+            PeerMessage::Port(message.get_u16())
+        }
+        20 => {
+            if length < 2 {
+                return Err(PeerMessageError::InvalidLength { id, length });
+            }
+            PeerMessage::Extended {
+                id: message.get_u8(),
+                payload: message.to_vec(),
+            }
+        }
+        _ => return Err(PeerMessageError::UnknownId(id)),
+    };
+    Ok(message)
+}
+
+// frames peer messages by their 4 byte big endian length prefix
+pub struct PeerCodec {
+    max_length: usize,
+}
+
+impl Default for PeerCodec {
+    fn default() -> PeerCodec {
+        PeerCodec {
+            max_length: MAX_MESSAGE_LENGTH,
+        }
+    }
+}
+
+impl Decoder for PeerCodec {
+    type Item = PeerMessage;
+    type Error = PeerMessageError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<PeerMessage>, PeerMessageError> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+        let length = u32::from_be_bytes(src[..4].try_into().unwrap()) as usize;
+        // checked before waiting for the rest, so a bogus prefix can't make us buffer gigabytes
+        if length > self.max_length {
+            return Err(PeerMessageError::TooLarge {
+                length,
+                max_length: self.max_length,
+            });
+        }
+        if src.len() < 4 + length {
+            src.reserve(4 + length - src.len());
+            return Ok(None);
+        }
+        src.advance(4);
+        let message = src.split_to(length);
+        decode(&message).map(Some)
+    }
+}
+
+impl Encoder<&PeerMessage> for PeerCodec {
+    type Error = PeerMessageError;
+
+    fn encode(&mut self, item: &PeerMessage, dst: &mut BytesMut) -> Result<(), PeerMessageError> {
+        encode(item, dst);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(message: &PeerMessage) -> BytesMut {
+        let mut dst = BytesMut::new();
+        encode(message, &mut dst);
+        dst
+    }
+
+    #[test]
+    fn round_trips_every_message() {
+        let messages = [
+            PeerMessage::KeepAlive,
+            PeerMessage::Choke,
+            PeerMessage::Unchoke,
+            PeerMessage::Interested,
+            PeerMessage::NotInterested,
+            PeerMessage::Have { index: 3 },
+            PeerMessage::Bitfield(vec![0b1010_0000, 0]),
+            PeerMessage::Request {
+                index: 1,
+                begin: 0x4000,
+                length: 0x4000,
+            },
+            PeerMessage::Piece {
+                index: 1,
+                begin: 0x4000,
+                block: vec![1, 2, 3],
+            },
+            PeerMessage::Cancel {
+                index: 1,
+                begin: 0x4000,
+                length: 0x4000,
+            },
+            PeerMessage::Port(6881),
+            PeerMessage::Extended {
+                id: 0,
+                payload: b"de".to_vec(),
+            },
+        ];
+        let mut src = BytesMut::new();
+        for message in &messages {
+            src.extend_from_slice(&encoded(message));
+        }
+        let mut codec = PeerCodec::default();
+        for message in messages {
+            assert_eq!(codec.decode(&mut src).unwrap(), Some(message));
+        }
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn waits_for_whole_frames() {
+        let frame = encoded(&PeerMessage::Have { index: 3 });
+        let mut codec = PeerCodec::default();
+        let mut src = BytesMut::new();
+        // the length prefix and the message each arrive in pieces
+        for &byte in &frame[..frame.len() - 1] {
+            src.extend_from_slice(&[byte]);
+            assert_eq!(codec.decode(&mut src).unwrap(), None);
+        }
+        src.extend_from_slice(&frame[frame.len() - 1..]);
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some(PeerMessage::Have { index: 3 })
+        );
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn rejects_oversized_frames_up_front() {
+        let mut src = BytesMut::new();
+        src.put_u32(MAX_MESSAGE_LENGTH as u32 + 1);
+        src.put_u8(7);
+        let result = PeerCodec::default().decode(&mut src);
+        assert!(matches!(
+            result,
+            Err(PeerMessageError::TooLarge { length, .. }) if length == MAX_MESSAGE_LENGTH + 1
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        assert!(matches!(
+            decode(&[4, 0, 0, 3]),
+            Err(PeerMessageError::InvalidLength { id: 4, length: 4 })
+        ));
+        assert!(matches!(
+            decode(&[7, 0, 0, 0, 1]),
+            Err(PeerMessageError::InvalidLength { id: 7, .. })
+        ));
+        assert!(matches!(
+            decode(&[21]),
+            Err(PeerMessageError::UnknownId(21))
+        ));
+    }
+}
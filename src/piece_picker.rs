@@ -0,0 +1,422 @@
+use crate::{bitfield::Bitfield, rng::Rng, torrent_info::TorrentInfo};
+
+// the size of a request, the last block of a piece may be shorter
+const BLOCK_SIZE: usize = 0x4000;
+// pieces picked at random before switching to rarest first, so there is something complete to
+// share as soon as possible
+const RANDOM_FIRST_PIECES: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Block {
+    pub piece_index: usize,
+    pub begin: usize,
+    pub length: usize,
+}
+
+pub enum Received {
+    // the block was already in, or its piece no longer needs it
+    Duplicate,
+    Block,
+    // the last missing block of a piece came in, this is the whole piece still to be hash checked
+    Piece(Vec<u8>),
+}
+
+enum PieceState {
+    Missing,
+    Partial {
+        // None until the block comes in
+        blocks: Vec<Option<Vec<u8>>>,
+        // how many peers each block is currently requested from
+        requested: Vec<usize>,
+    },
+    Complete,
+}
+
+// decides which blocks to request from which peer: partially downloaded pieces first, then
+// the rarest pieces among the connected peers, and once every block has been requested the
+// outstanding ones from several peers at once (endgame)
+pub struct PiecePicker {
+    pieces: Vec<PieceState>,
+    piece_sizes: Vec<usize>,
+    // how many connected peers have each piece
+    availability: Vec<usize>,
+    complete: usize,
+    rng: Rng,
+}
+
+impl PiecePicker {
+    // pieces in have are never picked
+    pub fn new(torrent_info: &TorrentInfo, have: &Bitfield) -> PiecePicker {
+        let piece_count = torrent_info.piece_hashes.len();
+        let pieces = (0..piece_count)
+            .map(|piece_index| {
+                if have.has(piece_index) {
+                    PieceState::Complete
+                } else {
+                    PieceState::Missing
+                }
+            })
+            .collect();
+        PiecePicker {
+            pieces,
+            piece_sizes: (0..piece_count)
+                .map(|piece_index| torrent_info.piece_size(piece_index))
+                .collect(),
+            availability: vec![0; piece_count],
+            complete: have.count(),
+            rng: Rng::from_time(),
+        }
+    }
+
+    pub fn add_peer(&mut self, bitfield: &Bitfield) {
+        for piece_index in 0..self.pieces.len() {
+            if bitfield.has(piece_index) {
+                self.availability[piece_index] += 1;
+            }
+        }
+    }
+
+    pub fn remove_peer(&mut self, bitfield: &Bitfield) {
+        for piece_index in 0..self.pieces.len() {
+            if bitfield.has(piece_index) {
+                self.availability[piece_index] -= 1;
+            }
+        }
+    }
+
+    // for a have message, only counts once per peer if the caller checks its bitfield first
+    pub fn add_have(&mut self, piece_index: usize) {
+        if let Some(availability) = self.availability.get_mut(piece_index) {
+            *availability += 1;
+        }
+    }
+
+    // up to count blocks the peer has that aren't in pending yet, marked as requested
+    pub fn pick(&mut self, bitfield: &Bitfield, pending: &[Block], count: usize) -> Vec<Block> {
+        let mut picked = Vec::new();
+        while picked.len() < count {
+            let piece_index = match self.best_partial(bitfield) {
+                Some(piece_index) => piece_index,
+                None => match self.new_piece(bitfield) {
+                    Some(piece_index) => piece_index,
+                    None => break,
+                },
+            };
+            picked.extend(self.request_blocks(
+                piece_index,
+                count - picked.len(),
+                |_, requested| requested == 0,
+            ));
+        }
+        if picked.len() < count && self.in_endgame() {
+            // everything is requested already, so ask for what is still outstanding elsewhere
+            for piece_index in 0..self.pieces.len() {
+                if picked.len() == count {
+                    break;
+                }
+                if !bitfield.has(piece_index) {
+                    continue;
+                }
+                picked.extend(self.request_blocks(
+                    piece_index,
+                    count - picked.len(),
+                    |block, _| !pending.contains(block),
+                ));
+            }
+        }
+        picked
+    }
+
+    // for requests that won't be answered anymore, e.g. because the peer choked us
+    pub fn cancel(&mut self, block: &Block) {
+        if let PieceState::Partial { requested, .. } = &mut self.pieces[block.piece_index] {
+            let requested = &mut requested[block.begin / BLOCK_SIZE];
+            *requested = requested.saturating_sub(1);
+        }
+    }
+
+    // whether a requested block is no longer needed, because it came in from somewhere else or
+    // its piece was dropped
+    pub fn is_stale(&self, block: &Block) -> bool {
+        match &self.pieces[block.piece_index] {
+            PieceState::Partial { blocks, .. } => blocks[block.begin / BLOCK_SIZE].is_some(),
+            _ => true,
+        }
+    }
+
+    // also returns whether other peers still have the block requested, as happens in endgame
+    pub fn received(&mut self, block: &Block, data: Vec<u8>) -> (Received, bool) {
+        let PieceState::Partial { blocks, requested } = &mut self.pieces[block.piece_index] else {
+            return (Received::Duplicate, false);
+        };
+        let block_index = block.begin / BLOCK_SIZE;
+        requested[block_index] = requested[block_index].saturating_sub(1);
+        let requested_elsewhere = requested[block_index] > 0;
+        if blocks[block_index].is_some() {
+            return (Received::Duplicate, requested_elsewhere);
+        }
+        blocks[block_index] = Some(data);
+        if blocks.iter().any(Option::is_none) {
+            return (Received::Block, requested_elsewhere);
+        }
+
+        let piece = blocks.iter_mut().flat_map(|block| block.take().unwrap());
+        let piece = piece.collect();
+        self.pieces[block.piece_index] = PieceState::Complete;
+        self.complete += 1;
+        (Received::Piece(piece), requested_elsewhere)
+    }
+
+    // for a piece that failed its hash check, it has to be downloaded again from scratch
+    pub fn piece_failed(&mut self, piece_index: usize) {
+        self.pieces[piece_index] = PieceState::Missing;
+        self.complete -= 1;
+    }
+
+    // the partial piece the peer has that needs the fewest blocks to be complete
+    fn best_partial(&self, bitfield: &Bitfield) -> Option<usize> {
+        let mut best = None;
+        let mut best_remaining = usize::MAX;
+        for (piece_index, piece) in self.pieces.iter().enumerate() {
+            let PieceState::Partial { blocks, requested } = piece else {
+                continue;
+            };
+            let unrequested = blocks
+                .iter()
+                .zip(requested)
+                .any(|(block, &requested)| block.is_none() && requested == 0);
+            if !unrequested || !bitfield.has(piece_index) {
+                continue;
+            }
+            let remaining = blocks.iter().filter(|block| block.is_none()).count();
+            if remaining < best_remaining {
+                best = Some(piece_index);
+                best_remaining = remaining;
+            }
+        }
+        best
+    }
+
+    // starts a missing piece the peer has, random for the first few and rarest after that
+    fn new_piece(&mut self, bitfield: &Bitfield) -> Option<usize> {
+        let candidates: Vec<usize> = (0..self.pieces.len())
+            .filter(|&piece_index| {
+                matches!(self.pieces[piece_index], PieceState::Missing) && bitfield.has(piece_index)
+            })
+            .collect();
+        let candidates = if self.complete < RANDOM_FIRST_PIECES {
+            candidates
+        } else {
+            let rarest = candidates
+                .iter()
+                .map(|&piece_index| self.availability[piece_index])
+                .min()?;
+            candidates
+                .into_iter()
+                .filter(|&piece_index| self.availability[piece_index] == rarest)
+                .collect()
+        };
+        if candidates.is_empty() {
+            return None;
+        }
+        // ties are broken at random so peers don't all go for the same pieces
+        let piece_index = candidates[self.rng.below(candidates.len())];
+
+        let block_count = self.piece_sizes[piece_index].div_ceil(BLOCK_SIZE);
+        self.pieces[piece_index] = PieceState::Partial {
+            blocks: vec![None; block_count],
+            requested: vec![0; block_count],
+        };
+        Some(piece_index)
+    }
+
+    // requests up to count blocks of a partial piece that haven't come in and that wanted accepts
+    fn request_blocks(
+        &mut self,
+        piece_index: usize,
+        count: usize,
+        wanted: impl Fn(&Block, usize) -> bool,
+    ) -> Vec<Block> {
+        let piece_size = self.piece_sizes[piece_index];
+        let PieceState::Partial { blocks, requested } = &mut self.pieces[piece_index] else {
+            return Vec::new();
+        };
+        let mut picked = Vec::new();
+        for (block_index, data) in blocks.iter().enumerate() {
+            if picked.len() == count {
+                break;
+            }
+            let begin = block_index * BLOCK_SIZE;
+            let block = Block {
+                piece_index,
+                begin,
+                length: BLOCK_SIZE.min(piece_size - begin),
+            };
+            if data.is_none() && wanted(&block, requested[block_index]) {
+                requested[block_index] += 1;
+                picked.push(block);
+            }
+        }
+        picked
+    }
+
+    // every block that isn't in yet has been requested from someone
+    fn in_endgame(&self) -> bool {
+        self.pieces.iter().all(|piece| match piece {
+            PieceState::Missing => false,
+            PieceState::Partial { blocks, requested } => blocks
+                .iter()
+                .zip(requested)
+                .all(|(block, &requested)| block.is_some() || requested > 0),
+            PieceState::Complete => true,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // two blocks to a piece
+    const PIECE_LENGTH: usize = 2 * BLOCK_SIZE;
+
+    fn torrent_info(piece_count: usize) -> TorrentInfo {
+        TorrentInfo {
+            trackers: vec![vec!["http://tracker/announce".to_owned()]],
+            name: "test".to_owned(),
+            length: piece_count * PIECE_LENGTH,
+            info_hash: vec![0; 20],
+            piece_length: PIECE_LENGTH,
+            piece_hashes: vec![vec![0; 20]; piece_count],
+            files: Vec::new(),
+            multi_file: false,
+        }
+    }
+
+    fn bitfield(piece_count: usize, pieces: &[usize]) -> Bitfield {
+        let mut bitfield = Bitfield::new(piece_count);
+        for &piece_index in pieces {
+            bitfield.set(piece_index);
+        }
+        bitfield
+    }
+
+    fn block(piece_index: usize, begin: usize) -> Block {
+        Block {
+            piece_index,
+            begin,
+            length: BLOCK_SIZE,
+        }
+    }
+
+    #[test]
+    fn picks_pieces_the_peer_has_at_random_at_first() {
+        let mut picker = PiecePicker::new(&torrent_info(8), &Bitfield::new(8));
+        let peer = bitfield(8, &[2, 5]);
+        picker.add_peer(&peer);
+
+        let picked = picker.pick(&peer, &[], 4);
+        assert_eq!(picked.len(), 4);
+        assert!(picked
+            .iter()
+            .all(|block| [2, 5].contains(&block.piece_index)));
+        // nothing else to ask this peer for
+        assert!(picker.pick(&peer, &picked, 4).is_empty());
+    }
+
+    #[test]
+    fn picks_the_rarest_piece_once_a_few_are_in() {
+        let have = bitfield(8, &[0, 1, 2, 3]);
+        let mut picker = PiecePicker::new(&torrent_info(8), &have);
+        let everything = bitfield(8, &[4, 5, 6, 7]);
+        let most = bitfield(8, &[4, 5, 7]);
+        picker.add_peer(&everything);
+        picker.add_peer(&most);
+        picker.add_peer(&most);
+
+        // only one peer has piece 6
+        let picked = picker.pick(&everything, &[], 2);
+        assert_eq!(picked, [block(6, 0), block(6, BLOCK_SIZE)]);
+
+        // availability follows peers coming and going, leaving piece 5 the rarest
+        picker.remove_peer(&most);
+        picker.remove_peer(&most);
+        picker.add_peer(&bitfield(8, &[4, 7]));
+        assert_eq!(picker.pick(&everything, &picked, 1), [block(5, 0)]);
+    }
+
+    #[test]
+    fn finishes_partial_pieces_first() {
+        let mut picker = PiecePicker::new(&torrent_info(4), &Bitfield::new(4));
+        let peer = bitfield(4, &[0, 1, 2, 3]);
+        picker.add_peer(&peer);
+
+        let first = picker.pick(&peer, &[], 1);
+        let other_peer = picker.pick(&peer, &[], 1);
+        assert_eq!(other_peer, [block(first[0].piece_index, BLOCK_SIZE)]);
+    }
+
+    #[test]
+    fn requests_outstanding_blocks_again_in_endgame() {
+        let mut picker = PiecePicker::new(&torrent_info(2), &Bitfield::new(2));
+        let peer = bitfield(2, &[0, 1]);
+        picker.add_peer(&peer);
+        picker.add_peer(&peer);
+
+        let pending = picker.pick(&peer, &[], 4);
+        assert_eq!(pending.len(), 4);
+        // never the same block twice from one peer
+        assert!(picker.pick(&peer, &pending, 4).is_empty());
+
+        let mut duplicates = picker.pick(&peer, &[], 4);
+        duplicates.sort_by_key(|block| (block.piece_index, block.begin));
+        let mut sorted = pending.clone();
+        sorted.sort_by_key(|block| (block.piece_index, block.begin));
+        assert_eq!(duplicates, sorted);
+
+        // the first copy in tells the caller the other is still out there, and makes it stale
+        let first = pending[0];
+        let (received, requested_elsewhere) = picker.received(&first, vec![0; BLOCK_SIZE]);
+        assert!(matches!(received, Received::Block));
+        assert!(requested_elsewhere);
+        assert!(picker.is_stale(&first));
+        let (received, requested_elsewhere) = picker.received(&first, vec![0; BLOCK_SIZE]);
+        assert!(matches!(received, Received::Duplicate));
+        assert!(!requested_elsewhere);
+    }
+
+    #[test]
+    fn cancelled_blocks_can_be_picked_again() {
+        let mut picker = PiecePicker::new(&torrent_info(4), &Bitfield::new(4));
+        let peer = bitfield(4, &[0, 1, 2, 3]);
+        picker.add_peer(&peer);
+
+        let picked = picker.pick(&peer, &[], 1);
+        assert!(!picker.is_stale(&picked[0]));
+        picker.cancel(&picked[0]);
+        assert!(!picker.is_stale(&picked[0]));
+        assert_eq!(picker.pick(&peer, &[], 1), picked);
+    }
+
+    #[test]
+    fn failed_pieces_start_over() {
+        let mut picker = PiecePicker::new(&torrent_info(1), &Bitfield::new(1));
+        let peer = bitfield(1, &[0]);
+        picker.add_peer(&peer);
+
+        let picked = picker.pick(&peer, &[], 2);
+        picker.received(&picked[0], vec![1; BLOCK_SIZE]);
+        let (received, _) = picker.received(&picked[1], vec![2; BLOCK_SIZE]);
+        let Received::Piece(piece) = received else {
+            panic!("the piece should be complete");
+        };
+        assert_eq!(piece.len(), PIECE_LENGTH);
+        assert_eq!(piece[BLOCK_SIZE - 1..BLOCK_SIZE + 1], [1, 2]);
+        assert!(picker.is_stale(&picked[0]));
+        assert!(picker.pick(&peer, &[], 2).is_empty());
+
+        picker.piece_failed(0);
+        assert!(picker.is_stale(&picked[0]));
+        assert_eq!(picker.pick(&peer, &[], 2), picked);
+    }
+}
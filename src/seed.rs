@@ -0,0 +1,244 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+
+use crate::{
+    bitfield::Bitfield,
+    choker::Choker,
+    peer_message::PeerMessage,
+    storage::Storage,
+    torrent_info::TorrentInfo,
+    torrent_protocol::{self, PeerError},
+    tracker::TransferStats,
+};
+
+// blocks are normally 16KiB, anything past this is refused
+const MAX_BLOCK_LENGTH: u32 = 0x20000;
+// stay well inside the two minutes a peer waits before giving up on a quiet connection
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(60);
+// how often the choker gets a chance to act on passing time
+const CHOKER_TICK: Duration = Duration::from_secs(1);
+// a failed accept, e.g. from running out of file descriptors, tends to fail again straight away
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+// accepts peers on port forever, serving them the pieces in have from storage and counting
+// what they're sent in stats
+pub async fn seed(
+    torrent_info: Arc<TorrentInfo>,
+    storage: Storage,
+    have: Bitfield,
+    port: u16,
+    stats: Arc<TransferStats>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    let storage = Arc::new(Mutex::new(storage));
+    let have = Arc::new(have);
+    let swarm = Arc::new(Mutex::new(Swarm::new(stats)));
+    let mut choker_tick = tokio::time::interval(CHOKER_TICK);
+    loop {
+        let (stream, address) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                // the peers already connected are still served
+                Err(err) => {
+                    eprintln!("unable to accept a peer: {err}");
+                    tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            },
+            _ = choker_tick.tick() => {
+                swarm.lock().unwrap().rechoke(Instant::now());
+                continue;
+            }
+        };
+        let torrent_info = torrent_info.clone();
+        let storage = storage.clone();
+        let have = have.clone();
+        let swarm = swarm.clone();
+        tokio::spawn(async move {
+            let result = serve_peer(stream, &torrent_info, &storage, &have, &swarm).await;
+            match result {
+                Ok(()) | Err(PeerError::Disconnected) => {}
+                Err(err) => eprintln!("peer {address}: {err}"),
+            }
+        });
+    }
+}
+
+async fn serve_peer(
+    stream: TcpStream,
+    torrent_info: &Arc<TorrentInfo>,
+    storage: &Arc<Mutex<Storage>>,
+    have: &Bitfield,
+    swarm: &Mutex<Swarm>,
+) -> Result<(), PeerError> {
+    let (mut writer, mut reader) = torrent_protocol::split(stream);
+    torrent_protocol::accept_handshake(torrent_info, &mut writer, &mut reader).await?;
+    torrent_protocol::send_messages(
+        &mut writer,
+        &[PeerMessage::Bitfield(have.as_bytes().to_vec())],
+    )
+    .await?;
+
+    let (choke_sender, mut choke_commands) = mpsc::unbounded_channel();
+    let swarm_id = swarm.lock().unwrap().join(choke_sender);
+    let mut keep_alive = tokio::time::interval(KEEP_ALIVE_INTERVAL);
+    // the first tick completes straight away
+    keep_alive.tick().await;
+
+    // the peer has to leave the swarm however the connection ends
+    let result = async {
+        let mut choked = true;
+        loop {
+            tokio::select! {
+                message = torrent_protocol::read_message(&mut reader) => {
+                    let response = handle_message(
+                        message?,
+                        choked,
+                        swarm_id,
+                        torrent_info,
+                        storage,
+                        have,
+                        swarm,
+                    )
+                    .await?;
+                    if let Some(response) = response {
+                        torrent_protocol::send_messages(&mut writer, &[response]).await?;
+                    }
+                }
+                Some(choke) = choke_commands.recv() => {
+                    choked = choke;
+                    let message = if choke { PeerMessage::Choke } else { PeerMessage::Unchoke };
+                    torrent_protocol::send_messages(&mut writer, &[message]).await?;
+                }
+                _ = keep_alive.tick() => {
+                    torrent_protocol::send_messages(&mut writer, &[PeerMessage::KeepAlive]).await?;
+                }
+            }
+        }
+    }
+    .await;
+    swarm.lock().unwrap().leave(swarm_id);
+    result
+}
+
+// returns the message to answer with, if any
+async fn handle_message(
+    message: PeerMessage,
+    choked: bool,
+    swarm_id: usize,
+    torrent_info: &Arc<TorrentInfo>,
+    storage: &Arc<Mutex<Storage>>,
+    have: &Bitfield,
+    swarm: &Mutex<Swarm>,
+) -> Result<Option<PeerMessage>, PeerError> {
+    match message {
+        PeerMessage::Interested => swarm.lock().unwrap().set_interested(swarm_id, true),
+        PeerMessage::NotInterested => swarm.lock().unwrap().set_interested(swarm_id, false),
+        // requests made while choked are dropped, the peer has to ask again once unchoked
+        PeerMessage::Request {
+            index,
+            begin,
+            length,
+        } if !choked => {
+            let piece_index = index as usize;
+            let in_piece = piece_index < torrent_info.piece_hashes.len()
+                && begin as usize + length as usize <= torrent_info.piece_size(piece_index);
+            if !have.has(piece_index) || !in_piece || length == 0 || length > MAX_BLOCK_LENGTH {
+                return Err(PeerError::InvalidRequest {
+                    index,
+                    begin,
+                    length,
+                });
+            }
+            // the disk is read off the runtime's worker threads, so other peers aren't held up
+            let (torrent_info, storage) = (torrent_info.clone(), storage.clone());
+            let block = tokio::task::spawn_blocking(move || {
+                storage.lock().unwrap().read_block(
+                    &torrent_info,
+                    piece_index,
+                    begin as usize,
+                    length as usize,
+                )
+            })
+            .await
+            .map_err(io::Error::from)??;
+            swarm
+                .lock()
+                .unwrap()
+                .uploaded(swarm_id, block.len(), Instant::now());
+            return Ok(Some(PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            }));
+        }
+        // requests are answered as soon as they arrive, so there's never anything to cancel.
+        // anything about the peer's own pieces doesn't matter while only seeding
+        _ => {}
+    }
+    Ok(None)
+}
+
+// the connected peers and the choker deciding which of them get served
+struct Swarm {
+    choker: Choker,
+    // true to choke the peer, false to unchoke it
+    choke_senders: HashMap<usize, mpsc::UnboundedSender<bool>>,
+    next_id: usize,
+    stats: Arc<TransferStats>,
+}
+
+impl Swarm {
+    fn new(stats: Arc<TransferStats>) -> Swarm {
+        Swarm {
+            // nothing is downloaded here, even with pieces missing, so peers can only be ranked
+            // by what they take from us
+            choker: Choker::new(true, Instant::now()),
+            choke_senders: HashMap::new(),
+            next_id: 0,
+            stats,
+        }
+    }
+
+    fn join(&mut self, choke_sender: mpsc::UnboundedSender<bool>) -> usize {
+        let swarm_id = self.next_id;
+        self.next_id += 1;
+        self.choker.add_peer(swarm_id);
+        self.choke_senders.insert(swarm_id, choke_sender);
+        swarm_id
+    }
+
+    fn leave(&mut self, swarm_id: usize) {
+        self.choker.remove_peer(swarm_id);
+        self.choke_senders.remove(&swarm_id);
+        self.rechoke(Instant::now());
+    }
+
+    fn set_interested(&mut self, swarm_id: usize, interested: bool) {
+        self.choker.set_interested(swarm_id, interested);
+        self.rechoke(Instant::now());
+    }
+
+    fn uploaded(&mut self, swarm_id: usize, bytes: usize, now: Instant) {
+        self.choker.record_transfer(swarm_id, bytes as u64, 0, now);
+        self.stats.add_uploaded(bytes as u64);
+    }
+
+    fn rechoke(&mut self, now: Instant) {
+        for (swarm_id, choked) in self.choker.update(now) {
+            if let Some(choke_sender) = self.choke_senders.get(&swarm_id) {
+                // a closed channel means the peer is on its way out and about to leave
+                let _ = choke_sender.send(choked);
+            }
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+
+use crate::{bitfield::Bitfield, torrent_info::TorrentInfo};
+
+#[derive(Debug, Error)]
+pub enum StorageError {
+    #[error("unable to open torrent files: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid file path in torrent: {0}")]
+    InvalidPath(String),
+}
+
+// maps the torrent's flat byte stream onto the files on disk
+pub struct Storage {
+    // None for files that don't exist when opened read only
+    files: Vec<Option<File>>,
+}
+
+impl Storage {
+    // single file torrents are written straight to output_path, multi file torrents
+    // use output_path as the root directory of the file tree. files that already exist are kept,
+    // so an interrupted download can pick up where it left off
+    pub fn open(torrent_info: &TorrentInfo, output_path: &str) -> Result<Storage, StorageError> {
+        let mut files = Vec::new();
+        let paths = file_paths(torrent_info, output_path)?;
+        for (path, torrent_file) in paths.into_iter().zip(&torrent_info.files) {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+            file.set_len(torrent_file.length as u64)?;
+            files.push(Some(file));
+        }
+        Ok(Storage { files })
+    }
+
+    // for looking at what's on disk without creating or resizing anything
+    pub fn open_read_only(
+        torrent_info: &TorrentInfo,
+        output_path: &str,
+    ) -> Result<Storage, StorageError> {
+        let files = file_paths(torrent_info, output_path)?
+            .into_iter()
+            .map(|path| File::open(path).ok())
+            .collect();
+        Ok(Storage { files })
+    }
+
+    pub fn read_piece(
+        &mut self,
+        torrent_info: &TorrentInfo,
+        piece_index: usize,
+    ) -> io::Result<Vec<u8>> {
+        self.read_block(
+            torrent_info,
+            piece_index,
+            0,
+            torrent_info.piece_size(piece_index),
+        )
+    }
+
+    // the caller makes sure the block lies within the piece
+    pub fn read_block(
+        &mut self,
+        torrent_info: &TorrentInfo,
+        piece_index: usize,
+        begin: usize,
+        length: usize,
+    ) -> io::Result<Vec<u8>> {
+        let mut data = vec![0; length];
+        let mut start = 0;
+        let offset = piece_index * torrent_info.piece_length + begin;
+        for span in torrent_info.file_spans(offset, length) {
+            let file = self.file(span.file_index)?;
+            file.seek(SeekFrom::Start(span.file_offset as u64))?;
+            file.read_exact(&mut data[start..start + span.length])?;
+            start += span.length;
+        }
+        Ok(data)
+    }
+
+    // hashes every piece already on disk, anything unreadable counts as missing
+    pub fn verify(&mut self, torrent_info: &TorrentInfo) -> Bitfield {
+        let mut valid = Bitfield::new(torrent_info.piece_hashes.len());
+        for (piece_index, piece_hash) in torrent_info.piece_hashes.iter().enumerate() {
+            if let Ok(data) = self.read_piece(torrent_info, piece_index) {
+                if Sha1::digest(&data).as_slice() == piece_hash.as_slice() {
+                    valid.set(piece_index);
+                }
+            }
+        }
+        valid
+    }
+
+    pub fn write_piece(
+        &mut self,
+        torrent_info: &TorrentInfo,
+        piece_index: usize,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let mut start = 0;
+        for span in torrent_info.file_spans(piece_index * torrent_info.piece_length, data.len()) {
+            let file = self.file(span.file_index)?;
+            file.seek(SeekFrom::Start(span.file_offset as u64))?;
+            file.write_all(&data[start..start + span.length])?;
+            start += span.length;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        for file in self.files.iter_mut().flatten() {
+            file.flush()?;
+        }
+        Ok(())
+    }
+
+    fn file(&mut self, file_index: usize) -> io::Result<&mut File> {
+        self.files[file_index]
+            .as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "file doesn't exist"))
+    }
+}
+
+fn file_paths(torrent_info: &TorrentInfo, output_path: &str) -> Result<Vec<PathBuf>, StorageError> {
+    if !torrent_info.multi_file {
+        return Ok(vec![PathBuf::from(output_path)]);
+    }
+    torrent_info
+        .files
+        .iter()
+        .map(|file| {
+            // paths come from untrusted metadata, don't let them escape the output directory
+            if file.path.is_empty() || !file.path.iter().all(|component| is_file_name(component)) {
+                return Err(StorageError::InvalidPath(file.path.join("/")));
+            }
+            let mut path = Path::new(output_path).to_path_buf();
+            path.extend(&file.path);
+            Ok(path)
+        })
+        .collect()
+}
+
+// a single plain name, not empty, "." or "..", and nothing a path would treat as a separator,
+// root or drive
+fn is_file_name(component: &str) -> bool {
+    let mut components = Path::new(component).components();
+    !component.contains(['/', '\\'])
+        && matches!(components.next(), Some(Component::Normal(_)))
+        && components.next().is_none()
+}
@@ -1,107 +1,235 @@
-use std::{collections::HashMap, fs::File, io::Read};
+use std::{collections::HashMap, fs};
 
+use serde::Deserialize;
 use sha1::{Digest, Sha1};
+use thiserror::Error;
 
-use crate::{
-    bformat::{bdecoder, bencoder},
-    buffered_stream::BufferedStream,
+use crate::bformat::{
+    bdecoder::{self, DecodeError, DecodeOptions},
+    bvalue::BValue,
+    de::{self, DeserializeError},
 };
 
+#[derive(Debug, Error)]
+pub enum TorrentInfoError {
+    #[error("unable to read torrent: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("malformed torrent: {0}")]
+    Decode(#[from] DecodeError),
+    #[error("malformed torrent: {0}")]
+    Deserialize(#[from] DeserializeError),
+    #[error("metadata doesn't match the info hash")]
+    InfoHashMismatch,
+}
+
+pub struct TorrentFile {
+    pub path: Vec<String>,
+    pub length: usize,
+    // offset of the first byte of this file within the concatenated torrent data
+    pub offset: usize,
+}
+
+// a contiguous run of bytes that lives inside a single file
+pub struct FileSpan {
+    pub file_index: usize,
+    pub file_offset: usize,
+    pub length: usize,
+}
+
 pub struct TorrentInfo {
-    pub url: String,
+    // tiers of tracker urls, tried in order. never empty, and neither is any tier
+    pub trackers: Vec<Vec<String>>,
+    pub name: String,
     pub length: usize,
     pub info_hash: Vec<u8>,
     pub piece_length: usize,
     pub piece_hashes: Vec<Vec<u8>>,
+    pub files: Vec<TorrentFile>,
+    pub multi_file: bool,
 }
 
-impl TorrentInfo {
-    pub fn from_file(filepath: &String) -> TorrentInfo {
-        let file = File::open(filepath).unwrap();
-        let mut buf_stream = BufferedStream::new(file);
-        let object = bdecoder::decode_map(&mut buf_stream);
+#[derive(Deserialize)]
+struct MetaInfo {
+    announce: Option<String>,
+    // BEP 12, replaces announce when present
+    #[serde(rename = "announce-list")]
+    announce_list: Option<Vec<Vec<String>>>,
+}
 
-        let url = object.get("announce").unwrap().to_string();
+#[derive(Deserialize)]
+struct Info<'a> {
+    name: String,
+    #[serde(rename = "piece length")]
+    piece_length: usize,
+    #[serde(borrow)]
+    pieces: &'a [u8],
+    // single file torrents have a length, multi file torrents have a list of files instead
+    length: Option<usize>,
+    files: Option<Vec<FileInfo>>,
+}
 
-        let info_btype = object.get("info").unwrap();
-        let mut hasher = Sha1::new();
-        hasher.update(bencoder::encode(info_btype));
-        let info_hash: Vec<u8> = hasher.finalize().iter().cloned().collect();
+#[derive(Deserialize)]
+struct FileInfo {
+    length: usize,
+    path: Vec<String>,
+}
 
-        let info = info_btype.as_map().unwrap();
-        let length = usize::try_from(*info.get("length").unwrap().as_number().unwrap()).unwrap();
+impl TorrentInfo {
+    pub fn from_file(filepath: &str) -> Result<TorrentInfo, TorrentInfoError> {
+        TorrentInfo::from_file_with_options(filepath, &DecodeOptions::default())
+    }
 
-        let piece_length =
-            usize::try_from(*info.get("piece length").unwrap().as_number().unwrap()).unwrap();
+    pub fn from_file_with_options(
+        filepath: &str,
+        options: &DecodeOptions,
+    ) -> Result<TorrentInfo, TorrentInfoError> {
+        TorrentInfo::from_bytes(&fs::read(filepath)?, options)
+    }
 
-        let mut piece_hashes = Vec::new();
-        let pieces = info.get("pieces").unwrap().as_bytes().unwrap();
-        let mut start = 0;
-        while start < pieces.len() {
-            piece_hashes.push(pieces[start..start + 20].iter().cloned().collect());
-            start += 20;
-        }
+    fn from_bytes(bytes: &[u8], options: &DecodeOptions) -> Result<TorrentInfo, TorrentInfoError> {
+        let object = bdecoder::decode_slice(bytes, options)?;
 
-        TorrentInfo {
-            url,
-            length,
-            info_hash,
-            piece_length,
-            piece_hashes,
+        let meta_info: MetaInfo = de::from_bvalue(&object)?;
+        let info = object
+            .as_map()
+            .and_then(|map| map.get("info"))
+            .ok_or(DeserializeError::MissingField("info"))?;
+        let mut trackers: Vec<Vec<String>> = meta_info
+            .announce_list
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|tier| !tier.is_empty())
+            .collect();
+        if trackers.is_empty() {
+            let announce = meta_info
+                .announce
+                .ok_or(DeserializeError::MissingField("announce"))?;
+            trackers.push(vec![announce]);
         }
+        TorrentInfo::from_info(trackers, info)
     }
 
-    pub fn from_metadata<T: Read>(
+    // metadata is the raw info dictionary as received from a peer's ut_metadata extension
+    pub fn from_metadata(
         partial_torrent_info: &TorrentInfo,
-        buf_stream: &mut BufferedStream<T>,
-    ) -> TorrentInfo {
-        let info_btype = bdecoder::decode(buf_stream);
+        metadata: &[u8],
+    ) -> Result<TorrentInfo, TorrentInfoError> {
+        let info = bdecoder::decode_slice(metadata, &DecodeOptions::default())?;
+        let torrent_info = TorrentInfo::from_info(partial_torrent_info.trackers.clone(), &info)?;
+        // any peer can send metadata, only that of the torrent the magnet link names will do
+        if torrent_info.info_hash != partial_torrent_info.info_hash {
+            return Err(TorrentInfoError::InfoHashMismatch);
+        }
+        Ok(torrent_info)
+    }
+
+    fn from_info(
+        trackers: Vec<Vec<String>>,
+        info_value: &BValue,
+    ) -> Result<TorrentInfo, TorrentInfoError> {
+        // checked up front since the hash needs the dictionary's raw bytes, and info comes from
+        // peers when resolving magnet links
+        let info_map = info_value.as_map().ok_or_else(|| DeserializeError::Field {
+            field: "info".to_owned(),
+            source: Box::new(DeserializeError::InvalidType {
+                expected: "a dictionary".to_owned(),
+                found: value_kind(info_value).to_owned(),
+            }),
+        })?;
+        let info: Info = de::from_bvalue(info_value)?;
+
+        // the info hash is taken over the original bytes rather than a re-encoding of the decoded
+        // dictionary, otherwise any non-canonically encoded info dictionary would hash differently
         let mut hasher = Sha1::new();
-        hasher.update(bencoder::encode(&info_btype));
-        let info_hash: Vec<u8> = hasher.finalize().iter().cloned().collect();
+        hasher.update(info_map.raw());
+        let info_hash: Vec<u8> = hasher.finalize().to_vec();
 
-        let info = info_btype.as_map().unwrap();
-        let length = usize::try_from(*info.get("length").unwrap().as_number().unwrap()).unwrap();
+        if !info.pieces.chunks_exact(20).remainder().is_empty() {
+            return Err(DeserializeError::InvalidValue {
+                expected: "a multiple of 20 bytes of piece hashes".to_owned(),
+                found: format!("{} bytes", info.pieces.len()),
+            }
+            .into());
+        }
 
-        let piece_length =
-            usize::try_from(*info.get("piece length").unwrap().as_number().unwrap()).unwrap();
+        let multi_file = info.files.is_some();
+        let files = match info.files {
+            Some(file_infos) => {
+                let mut files = Vec::new();
+                let mut offset = 0;
+                for file_info in file_infos {
+                    files.push(TorrentFile {
+                        path: file_info.path,
+                        length: file_info.length,
+                        offset,
+                    });
+                    offset += file_info.length;
+                }
+                files
+            }
+            None => vec![TorrentFile {
+                path: vec![info.name.clone()],
+                length: info
+                    .length
+                    .ok_or(DeserializeError::MissingField("length"))?,
+                offset: 0,
+            }],
+        };
+        let length: usize = files.iter().map(|file| file.length).sum();
 
-        let mut piece_hashes = Vec::new();
-        let pieces = info.get("pieces").unwrap().as_bytes().unwrap();
-        let mut start = 0;
-        while start < pieces.len() {
-            piece_hashes.push(pieces[start..start + 20].iter().cloned().collect());
-            start += 20;
+        // piece_size and everything working out piece offsets relies on a hash for every piece
+        // and no more
+        if info.piece_length == 0 {
+            return Err(DeserializeError::InvalidValue {
+                expected: "a positive piece length".to_owned(),
+                found: "0".to_owned(),
+            }
+            .into());
         }
+        let piece_count = info.pieces.len() / 20;
+        let expected_piece_count = length.div_ceil(info.piece_length);
+        if piece_count != expected_piece_count {
+            return Err(DeserializeError::InvalidValue {
+                expected: format!("{expected_piece_count} piece hashes for {length} bytes"),
+                found: format!("{piece_count}"),
+            }
+            .into());
+        }
+
+        let piece_hashes = info.pieces.chunks(20).map(|hash| hash.to_vec()).collect();
 
-        TorrentInfo {
-            url: partial_torrent_info.url.clone(),
+        Ok(TorrentInfo {
+            trackers,
+            name: info.name,
             length,
             info_hash,
-            piece_length,
+            piece_length: info.piece_length,
             piece_hashes,
-        }
+            files,
+            multi_file,
+        })
     }
 
-    pub fn from_link(link: &String) -> Result<TorrentInfo, String> {
+    pub fn from_link(link: &str) -> Result<TorrentInfo, String> {
         let parts: Vec<&str> = link[8..].split('&').collect();
 
         let mut info_hash_option: Option<Vec<u8>> = None;
         let mut file_name_option: Option<String> = None;
-        let mut url_option: Option<String> = None;
+        let mut urls: Vec<String> = Vec::new();
         for part in parts {
-            if part.starts_with("xt=urn:btih:") {
-                info_hash_option = hex::decode(&part[12..]).ok();
+            if let Some(info_hash) = part.strip_prefix("xt=urn:btih:") {
+                info_hash_option = hex::decode(info_hash).ok();
             }
-            if part.starts_with("dn=") {
-                file_name_option = Some(part[3..].to_owned());
+            if let Some(file_name) = part.strip_prefix("dn=") {
+                file_name_option = Some(file_name.to_owned());
             }
-            if part.starts_with("tr=") {
+            if let Some(url) = part.strip_prefix("tr=") {
                 // deserializes into a map of {"url", ""} for some reason, have to do the awful mapping to turn it back into a string
-                url_option =
-                    serde_urlencoded::from_str::<HashMap<String, String>>(&part[3..].to_owned())
+                urls.extend(
+                    serde_urlencoded::from_str::<HashMap<String, String>>(url)
                         .ok()
-                        .map(|u| u.keys().collect::<Vec<&String>>().pop().unwrap().to_owned());
+                        .map(|u| u.keys().collect::<Vec<&String>>().pop().unwrap().to_owned()),
+                );
             }
         }
 
@@ -111,16 +239,118 @@ impl TorrentInfo {
         if file_name_option.is_none() {
             return Err("Magnet link is missing file name!".to_owned());
         }
-        if url_option.is_none() {
+        if urls.is_empty() {
             return Err("Magnet link is missing tracker url!".to_owned());
         }
-        return Ok(TorrentInfo {
-            url: url_option.unwrap(),
+        Ok(TorrentInfo {
+            // magnet links don't rank their trackers, so they all share one tier
+            trackers: vec![urls],
+            name: file_name_option.unwrap(),
             length: 999, // needs to be greater than 0 for handshake
             info_hash: info_hash_option.unwrap(),
             piece_length: 0,
             piece_hashes: Vec::new(),
-            // file_name: file_name_option.unwrap(),
-        });
+            files: Vec::new(),
+            multi_file: false,
+        })
+    }
+
+    // the tracker a single tracker client would use
+    pub fn tracker_url(&self) -> &str {
+        &self.trackers[0][0]
+    }
+
+    pub fn piece_size(&self, piece_index: usize) -> usize {
+        self.piece_length
+            .min(self.length - self.piece_length * piece_index)
+    }
+
+    // splits the byte range [offset, offset + length) of the torrent data into per-file spans
+    pub fn file_spans(&self, offset: usize, length: usize) -> Vec<FileSpan> {
+        let end = offset + length;
+        let mut spans = Vec::new();
+        for (file_index, file) in self.files.iter().enumerate() {
+            let file_end = file.offset + file.length;
+            if file_end <= offset || file.offset >= end {
+                continue;
+            }
+            let span_start = offset.max(file.offset);
+            let span_end = end.min(file_end);
+            spans.push(FileSpan {
+                file_index,
+                file_offset: span_start - file.offset,
+                length: span_end - span_start,
+            });
+        }
+        spans
+    }
+}
+
+fn value_kind(value: &BValue) -> &'static str {
+    match value {
+        BValue::Bytes(_) => "a byte string",
+        BValue::Number(_) => "an integer",
+        BValue::List(_) => "a list",
+        BValue::Map(_) => "a dictionary",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn torrent(info: &str) -> Vec<u8> {
+        format!("d8:announce16:http://tracker/a4:info{info}e").into_bytes()
+    }
+
+    fn hashes(count: usize) -> String {
+        format!("6:pieces{}:{}", count * 20, "h".repeat(count * 20))
+    }
+
+    #[test]
+    fn reads_the_piece_geometry() {
+        let info = format!("d6:lengthi10e4:name1:a12:piece lengthi4e{}e", hashes(3));
+        let torrent_info =
+            TorrentInfo::from_bytes(&torrent(&info), &DecodeOptions::default()).unwrap();
+        assert_eq!(torrent_info.piece_hashes.len(), 3);
+        assert_eq!(torrent_info.piece_size(1), 4);
+        assert_eq!(torrent_info.piece_size(2), 2);
+    }
+
+    #[test]
+    fn checks_metadata_against_the_info_hash() {
+        let info = format!("d6:lengthi10e4:name1:a12:piece lengthi4e{}e", hashes(3));
+        let info_hash = hex::encode(Sha1::digest(info.as_bytes()));
+        let magnet = format!("magnet:?xt=urn:btih:{info_hash}&dn=a&tr=http%3A%2F%2Ftracker%2Fa");
+        let partial_torrent_info = TorrentInfo::from_link(&magnet).unwrap();
+        let torrent_info =
+            TorrentInfo::from_metadata(&partial_torrent_info, info.as_bytes()).unwrap();
+        assert_eq!(torrent_info.length, 10);
+
+        let other_info = info.replace("1:a", "1:b");
+        let result = TorrentInfo::from_metadata(&partial_torrent_info, other_info.as_bytes());
+        assert!(matches!(result, Err(TorrentInfoError::InfoHashMismatch)));
+    }
+
+    #[test]
+    fn rejects_bad_piece_geometry() {
+        for info in [
+            // a piece hash too many
+            format!("d6:lengthi10e4:name1:a12:piece lengthi4e{}e", hashes(4)),
+            // and one too few
+            format!("d6:lengthi10e4:name1:a12:piece lengthi4e{}e", hashes(2)),
+            format!("d6:lengthi10e4:name1:a12:piece lengthi0e{}e", hashes(3)),
+        ] {
+            let result = TorrentInfo::from_bytes(&torrent(&info), &DecodeOptions::default());
+            assert!(
+                matches!(
+                    result,
+                    Err(TorrentInfoError::Deserialize(
+                        DeserializeError::InvalidValue { .. }
+                    ))
+                ),
+                "{info}"
+            );
+        }
     }
 }
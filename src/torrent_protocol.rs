@@ -1,220 +1,450 @@
 use std::{
     cmp::min,
     collections::{HashMap, VecDeque},
-    io::{Read, Write},
+    io,
+    time::Duration,
 };
 
-use bytes::Buf;
+use bytes::{Buf, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream, ToSocketAddrs,
+    },
+};
 
 use crate::{
-    bformat::{bdecoder, bencoder, btype::BType},
+    async_buffered_stream::AsyncBufferedStream,
+    bformat::{
+        bdecoder::{self, DecodeError},
+        bencoder,
+        de::{self, DeserializeError},
+        ser,
+    },
     buffered_stream::BufferedStream,
-    torrent_info::TorrentInfo,
+    codec::Encoder,
+    peer_message::{PeerCodec, PeerMessage, PeerMessageError},
+    torrent_info::{TorrentInfo, TorrentInfoError},
+    tracker::{self, AnnounceEvent, AnnounceResponse, Progress, ScrapeResponse, TrackerTiers},
+    udp_tracker::UdpTrackerError,
 };
 
-pub async fn discovery(torrent_info: &TorrentInfo) -> BType {
-    let params = HashMap::from([
-        ("peer_id", "1234567890abcdefghij".to_owned()),
-        ("port", "6881".to_owned()),
-        ("uploaded", "0".to_owned()),
-        ("downloaded", "0".to_owned()),
-        ("left", format!("{}", torrent_info.length)),
+// peers send a keep-alive at least every two minutes, anything quieter is considered gone
+const PEER_READ_TIMEOUT: Duration = Duration::from_secs(120);
+const PEER_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// an http tracker that hasn't answered by then is given up on, so the next one gets a turn
+const HTTP_TRACKER_TIMEOUT: Duration = Duration::from_secs(30);
+pub const PEER_ID: &[u8; 20] = b"1234567890abcdefghij";
+// the port advertised to trackers and listened on when seeding
+pub const LISTEN_PORT: u16 = 6881;
+
+#[derive(Debug, Error)]
+pub enum PeerError {
+    #[error("peer closed the connection")]
+    Disconnected,
+    #[error("peer connection failed: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid message from peer: {0}")]
+    Message(PeerMessageError),
+    #[error("malformed message from peer: {0}")]
+    Deserialize(#[from] DeserializeError),
+    #[error("invalid metadata from peer: {0}")]
+    TorrentInfo(#[from] TorrentInfoError),
+    #[error("piece index {0} out of range")]
+    PieceOutOfRange(usize),
+    #[error("peer choked us")]
+    Choked,
+    #[error("piece {0} failed its hash check")]
+    HashMismatch(usize),
+    #[error("invalid handshake from peer")]
+    InvalidHandshake,
+    #[error("peer asked for a torrent we don't have")]
+    UnknownTorrent,
+    #[error("invalid request for {length} bytes at {begin} in piece {index}")]
+    InvalidRequest { index: u32, begin: u32, length: u32 },
+    #[error("unexpected message from peer, expected {0}")]
+    UnexpectedMessage(&'static str),
+}
+
+#[derive(Debug, Error)]
+pub enum TrackerError {
+    #[error("tracker request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("invalid response from tracker: {0}")]
+    Decode(#[from] DecodeError),
+    #[error(transparent)]
+    Udp(UdpTrackerError),
+    #[error("unsupported tracker url: {0}")]
+    UnsupportedUrl(String),
+    #[error("tracker refused the request: {0}")]
+    Failure(String),
+    #[error("invalid `{0}` in tracker response")]
+    InvalidResponse(&'static str),
+    #[error("tracker doesn't support scrape: {0}")]
+    ScrapeUnsupported(String),
+}
+
+// a udp tracker's error message is a failure reason like any other
+impl From<UdpTrackerError> for TrackerError {
+    fn from(err: UdpTrackerError) -> TrackerError {
+        match err {
+            UdpTrackerError::Failure(reason) => TrackerError::Failure(reason),
+            err => TrackerError::Udp(err),
+        }
+    }
+}
+
+// io errors stay io errors whether they came up while framing a message or not
+impl From<PeerMessageError> for PeerError {
+    fn from(err: PeerMessageError) -> PeerError {
+        match err {
+            PeerMessageError::Io(err) => PeerError::Io(err),
+            err => PeerError::Message(err),
+        }
+    }
+}
+
+// a single announce to the torrent's trackers, see TrackerTiers for which ones
+pub async fn discovery(torrent_info: &TorrentInfo) -> Result<AnnounceResponse, TrackerError> {
+    let progress = Progress {
+        uploaded: 0,
+        downloaded: 0,
+        left: torrent_info.length as u64,
+    };
+    TrackerTiers::new(&torrent_info.trackers)
+        .announce(torrent_info, AnnounceEvent::None, progress)
+        .await
+}
+
+// tracker_id is whatever the tracker handed out on an earlier announce
+pub async fn http_announce(
+    torrent_info: &TorrentInfo,
+    url: &str,
+    tracker_id: Option<&str>,
+    event: AnnounceEvent,
+    progress: Progress,
+) -> Result<AnnounceResponse, TrackerError> {
+    let mut params = HashMap::from([
+        ("peer_id", String::from_utf8(PEER_ID.to_vec()).unwrap()),
+        ("port", LISTEN_PORT.to_string()),
+        ("uploaded", progress.uploaded.to_string()),
+        ("downloaded", progress.downloaded.to_string()),
+        ("left", progress.left.to_string()),
         ("compact", "1".to_owned()),
     ]);
+    if let Some(tracker_id) = tracker_id {
+        params.insert("trackerid", tracker_id.to_owned());
+    }
+    let event = match event {
+        AnnounceEvent::None => None,
+        AnnounceEvent::Started => Some("started"),
+        AnnounceEvent::Completed => Some("completed"),
+        AnnounceEvent::Stopped => Some("stopped"),
+    };
+    if let Some(event) = event {
+        params.insert("event", event.to_owned());
+    }
+
+    let mut url = reqwest::Url::parse_with_params(url, params)
+        .map_err(|_| TrackerError::UnsupportedUrl(url.to_owned()))?;
+    append_info_hash(&mut url, &torrent_info.info_hash);
 
-    let mut url = reqwest::Url::parse_with_params(torrent_info.url.as_str(), params).unwrap();
-    url.query_pairs_mut().append_pair("info_hash", unsafe {
-        std::str::from_utf8_unchecked(&torrent_info.info_hash)
-    });
-
-    let mut response_reader = BufferedStream::new(
-        reqwest::get(url)
-            .await
-            .unwrap()
-            .bytes()
-            .await
-            .unwrap()
-            .reader(),
-    );
-    bdecoder::decode(&mut response_reader)
+    let response = http_get(url).await?;
+    AnnounceResponse::from_btype(&bdecoder::decode(&mut BufferedStream::new(
+        response.reader(),
+    ))?)
+}
+
+// the swarms of every torrent in info_hashes in one request
+pub async fn http_scrape(url: &str, info_hashes: &[&[u8]]) -> Result<ScrapeResponse, TrackerError> {
+    let mut scrape_url =
+        tracker::scrape_url(url).ok_or_else(|| TrackerError::ScrapeUnsupported(url.to_owned()))?;
+    for info_hash in info_hashes {
+        append_info_hash(&mut scrape_url, info_hash);
+    }
+
+    let response = http_get(scrape_url).await?;
+    ScrapeResponse::from_btype(&bdecoder::decode(&mut BufferedStream::new(
+        response.reader(),
+    ))?)
+}
+
+// info hashes are raw bytes rather than text, so they are escaped by hand: every byte but the
+// unreserved characters of RFC 3986 becomes %XX
+fn append_info_hash(url: &mut reqwest::Url, info_hash: &[u8]) {
+    let mut query = url
+        .query()
+        .map(|query| format!("{query}&"))
+        .unwrap_or_default();
+    query.push_str("info_hash=");
+    for &byte in info_hash {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            query.push(byte as char);
+        } else {
+            query.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    url.set_query(Some(&query));
+}
+
+async fn http_get(url: reqwest::Url) -> Result<Bytes, TrackerError> {
+    let request = reqwest::Client::new()
+        .get(url)
+        .timeout(HTTP_TRACKER_TIMEOUT);
+    Ok(request.send().await?.bytes().await?)
+}
+
+pub async fn connect(
+    address: impl ToSocketAddrs,
+) -> io::Result<(OwnedWriteHalf, AsyncBufferedStream<OwnedReadHalf>)> {
+    let stream = tokio::time::timeout(PEER_CONNECT_TIMEOUT, TcpStream::connect(address))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))??;
+    Ok(split(stream))
+}
+
+// splits a peer connection so the halves can be read and written independently
+pub fn split(stream: TcpStream) -> (OwnedWriteHalf, AsyncBufferedStream<OwnedReadHalf>) {
+    let (reader, writer) = stream.into_split();
+    let mut reader = AsyncBufferedStream::new(reader);
+    reader.set_read_timeout(Some(PEER_READ_TIMEOUT));
+    (writer, reader)
 }
 
 // return value is in the form of (Peer ID, peer reserved bytes)
-pub fn handshake<T: Read>(
+pub async fn handshake<T: AsyncRead + Unpin>(
     torrent_info: &TorrentInfo,
-    writer: &mut impl Write,
-    reader: &mut BufferedStream<T>,
+    writer: &mut (impl AsyncWrite + Unpin),
+    reader: &mut AsyncBufferedStream<T>,
     reserved_bytes: Option<[u8; 8]>,
-) -> (Vec<u8>, Vec<u8>) {
+) -> Result<(Vec<u8>, Vec<u8>), PeerError> {
+    send_handshake(writer, torrent_info, reserved_bytes).await?;
+    let (peer_reserved_bytes, info_hash, peer_id) = read_handshake(reader).await?;
+    if info_hash != torrent_info.info_hash {
+        return Err(PeerError::InvalidHandshake);
+    }
+
+    Ok((peer_id, peer_reserved_bytes))
+}
+
+// the other side of handshake for connections a peer opened, the peer goes first and only gets
+// an answer if it asks for our torrent. returns the Peer ID
+pub async fn accept_handshake<T: AsyncRead + Unpin>(
+    torrent_info: &TorrentInfo,
+    writer: &mut (impl AsyncWrite + Unpin),
+    reader: &mut AsyncBufferedStream<T>,
+) -> Result<Vec<u8>, PeerError> {
+    let (_, info_hash, peer_id) = read_handshake(reader).await?;
+    if info_hash != torrent_info.info_hash {
+        return Err(PeerError::UnknownTorrent);
+    }
+    send_handshake(writer, torrent_info, None).await?;
+
+    Ok(peer_id)
+}
+
+async fn send_handshake(
+    writer: &mut (impl AsyncWrite + Unpin),
+    torrent_info: &TorrentInfo,
+    reserved_bytes: Option<[u8; 8]>,
+) -> Result<(), PeerError> {
     let mut handshake_message: Vec<u8> = Vec::new();
     handshake_message.push(19);
-    handshake_message.append(&mut "BitTorrent protocol".bytes().collect());
-    handshake_message.append(&mut Vec::from(
-        reserved_bytes.unwrap_or([0, 0, 0, 0, 0, 0, 0, 0]),
-    ));
-    handshake_message.append(&mut torrent_info.info_hash.clone());
-    handshake_message.append(&mut "1234567890abcdefghij".bytes().collect());
-
-    writer.write_all(&handshake_message).unwrap();
-    writer.flush().unwrap();
-
-    assert_eq!(handshake_message[..20], reader.read_n_bytes(20).unwrap());
-    let peer_reserved_bytes = reader.read_n_bytes(8).unwrap();
-    assert_eq!(torrent_info.info_hash, reader.read_n_bytes(20).unwrap());
-    let peer_id = reader.read_n_bytes(20).unwrap();
-
-    (peer_id, peer_reserved_bytes)
+    handshake_message.extend_from_slice(b"BitTorrent protocol");
+    handshake_message.extend_from_slice(&reserved_bytes.unwrap_or([0, 0, 0, 0, 0, 0, 0, 0]));
+    handshake_message.extend_from_slice(&torrent_info.info_hash);
+    handshake_message.extend_from_slice(PEER_ID);
+
+    writer.write_all(&handshake_message).await?;
+    writer.flush().await?;
+    Ok(())
 }
 
-pub fn extension_handshake<T: Read>(writer: &mut impl Write, reader: &mut BufferedStream<T>) -> u8 {
+// return value is in the form of (reserved bytes, info hash, Peer ID)
+async fn read_handshake<T: AsyncRead + Unpin>(
+    reader: &mut AsyncBufferedStream<T>,
+) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>), PeerError> {
+    if reader.peek_byte().await?.is_none() {
+        return Err(PeerError::Disconnected);
+    }
+    if reader.read_byte().await? != 19 || reader.read_n_bytes(19).await? != b"BitTorrent protocol" {
+        return Err(PeerError::InvalidHandshake);
+    }
+    let reserved_bytes = reader.read_n_bytes(8).await?;
+    let info_hash = reader.read_n_bytes(20).await?;
+    let peer_id = reader.read_n_bytes(20).await?;
+
+    Ok((reserved_bytes, info_hash, peer_id))
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExtensionHandshake {
+    m: ExtensionIds,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExtensionIds {
+    ut_metadata: u8,
+}
+
+// header of a ut_metadata message, any metadata piece data follows directly after it
+#[derive(Serialize, Deserialize)]
+struct MetadataMessage {
+    msg_type: u8,
+    piece: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_size: Option<usize>,
+}
+
+pub async fn extension_handshake<T: AsyncRead + Unpin>(
+    writer: &mut (impl AsyncWrite + Unpin),
+    reader: &mut AsyncBufferedStream<T>,
+) -> Result<u8, PeerError> {
     // wait for bitfield
-    let _ = read_peer_message(reader);
-
-    let mut message = bencoder::encode(&BType::Map(HashMap::from([(
-        "m".to_owned(),
-        Box::new(BType::Map(HashMap::from([(
-            "ut_metadata".to_owned(),
-            Box::new(BType::Number(1)),
-        )]))),
-    )])));
-    writer
-        .write_all(&mut to_vec((message.len() + 2) as u32))
-        .unwrap();
-    writer.write_all(&mut vec![20, 0]).unwrap();
-    writer.write_all(&mut message).unwrap();
-    writer.flush().unwrap();
-
-    let response = read_peer_message(reader);
-    assert_eq!(response[..2], vec![20, 0][..2], "message id's didn't match");
-    let btype = bdecoder::decode(&mut BufferedStream::new(response[2..].reader()));
-    let map = btype.as_map().unwrap();
-    assert!(
-        map.contains_key("m"),
-        "response dictionary didn't have an 'm' entry"
-    );
-    let extension_map = map.get("m").unwrap().as_map().unwrap();
-    assert!(
-        extension_map.contains_key("ut_metadata"),
-        "extension dictionary didn't contain an entry for 'ut_metadata'"
-    );
-    *extension_map
-        .get("ut_metadata")
-        .unwrap()
-        .as_number()
-        .unwrap() as u8
+    read_message(reader).await?;
+
+    send_extension_message(
+        writer,
+        0,
+        &ExtensionHandshake {
+            m: ExtensionIds { ut_metadata: 1 },
+        },
+    )
+    .await?;
+
+    let PeerMessage::Extended { id: 0, payload } = read_message(reader).await? else {
+        return Err(PeerError::UnexpectedMessage("an extension handshake"));
+    };
+    let handshake: ExtensionHandshake = de::from_slice(&payload)?;
+    Ok(handshake.m.ut_metadata)
 }
 
-pub fn request_metadata<T: Read>(
+pub async fn request_metadata<T: AsyncRead + Unpin>(
     partial_torrent_info: &TorrentInfo,
     metadata_id: u8,
-    writer: &mut impl Write,
-    reader: &mut BufferedStream<T>,
-) -> TorrentInfo {
-    let message = bencoder::encode(&BType::Map(HashMap::from([
-        ("msg_type".to_owned(), Box::new(BType::Number(0))),
-        ("piece".to_owned(), Box::new(BType::Number(0))),
-    ])));
-
-    writer
-        .write_all(&mut to_vec((message.len() + 2) as u32))
-        .unwrap();
-    writer.write_all(&mut [20, metadata_id]).unwrap();
-    writer.write_all(&message).unwrap();
-    writer.flush().unwrap();
-
-    let response = read_peer_message(reader);
-    // not using metadata_id as second variable here because we sent 1 as our metadata id during handshake
-    assert_eq!(response[..2], vec![20, 1]);
-
-    let mut response_stream = BufferedStream::new(response[2..].reader());
-    let btype = bdecoder::decode(&mut response_stream);
-    let map = btype.as_map().unwrap();
-    assert!(map.contains_key("msg_type"));
-    assert_eq!(map.get("msg_type").unwrap().as_number(), Some(&1));
-    assert!(map.contains_key("piece"));
-    assert_eq!(map.get("piece").unwrap().as_number(), Some(&0));
-
-    TorrentInfo::from_metadata(partial_torrent_info, &mut response_stream)
+    writer: &mut (impl AsyncWrite + Unpin),
+    reader: &mut AsyncBufferedStream<T>,
+) -> Result<TorrentInfo, PeerError> {
+    send_extension_message(
+        writer,
+        metadata_id,
+        &MetadataMessage {
+            msg_type: 0,
+            piece: 0,
+            total_size: None,
+        },
+    )
+    .await?;
+
+    // not matching on metadata_id here because we sent 1 as our metadata id during handshake
+    let PeerMessage::Extended { id: 1, payload } = read_message(reader).await? else {
+        return Err(PeerError::UnexpectedMessage("a metadata message"));
+    };
+
+    let mut payload_stream = BufferedStream::new(payload.as_slice());
+    let header: MetadataMessage = de::from_stream(&mut payload_stream)?;
+    // anything but the data for the piece we asked for, e.g. a reject
+    if header.msg_type != 1 || header.piece != 0 {
+        return Err(PeerError::UnexpectedMessage("the metadata piece requested"));
+    }
+
+    // the metadata piece follows directly after the bencoded header
+    let metadata = &payload[payload_stream.position()..];
+    Ok(TorrentInfo::from_metadata(partial_torrent_info, metadata)?)
 }
 
-pub fn send_interested<T: Read>(writer: &mut impl Write, reader: &mut BufferedStream<T>) {
-    writer.write_all(&mut vec![0, 0, 0, 1, 2]).unwrap();
-    writer.flush().unwrap();
+async fn send_extension_message(
+    writer: &mut (impl AsyncWrite + Unpin),
+    extension_id: u8,
+    payload: &impl Serialize,
+) -> Result<(), PeerError> {
+    let payload = ser::to_btype(payload).unwrap();
+    let mut encoded = Vec::with_capacity(bencoder::encoded_len(&payload));
+    bencoder::encode_to(&payload, &mut encoded).unwrap();
+    send_messages(
+        writer,
+        &[PeerMessage::Extended {
+            id: extension_id,
+            payload: encoded,
+        }],
+    )
+    .await
+}
+
+pub async fn send_interested<T: AsyncRead + Unpin>(
+    writer: &mut (impl AsyncWrite + Unpin),
+    reader: &mut AsyncBufferedStream<T>,
+) -> Result<(), PeerError> {
+    send_messages(writer, &[PeerMessage::Interested]).await?;
 
     // receive unchoke
-    loop {
-        let message = read_peer_message(reader);
-        if message.len() > 0 && message[0] == 1 {
-            break;
-        }
-    }
+    while read_message(reader).await? != PeerMessage::Unchoke {}
+    Ok(())
 }
 
-pub fn download_piece<T: Read>(
+pub async fn download_piece<T: AsyncRead + Unpin>(
     torrent_info: &TorrentInfo,
     piece_index: usize,
-    writer: &mut impl Write,
-    reader: &mut BufferedStream<T>,
-) -> Result<Vec<u8>, String> {
+    writer: &mut (impl AsyncWrite + Unpin),
+    reader: &mut AsyncBufferedStream<T>,
+) -> Result<Vec<u8>, PeerError> {
     if piece_index >= torrent_info.piece_hashes.len() {
-        return Err(format!("Error: piece index {piece_index} out of range!"));
+        return Err(PeerError::PieceOutOfRange(piece_index));
     }
+    let index = piece_index as u32;
 
     // vec of (begin, length)
     let mut blocks_needed: VecDeque<(u32, u32)> = VecDeque::new();
-    let piece_size: usize = min(
-        torrent_info.piece_length,
-        torrent_info.length - (torrent_info.piece_length * piece_index),
-    );
-    let mut block_count = piece_size / 0x4000;
-    if piece_size % 0x4000 != 0 {
-        block_count += 1;
-    }
+    let piece_size = torrent_info.piece_size(piece_index);
+    let block_count = piece_size.div_ceil(0x4000);
     for i in 0..block_count {
-        blocks_needed.push_back((
-            (i * 0x4000) as u32,
-            if piece_size % 0x4000 != 0 && i == block_count - 1 {
-                (piece_size % 0x4000) as u32
-            } else {
-                0x4000
-            },
-        ));
+        let begin = i * 0x4000;
+        blocks_needed.push_back((begin as u32, min(0x4000, piece_size - begin) as u32));
     }
 
     // request & receive blocks
-    let mut piece = Vec::new();
-    let mut pending: VecDeque<(u32, u32)> = VecDeque::new();
-    while blocks_needed.len() > 0 || pending.len() > 0 {
-        while pending.len() < 5 && blocks_needed.len() > 0 {
-            // request up to 5 items
-            let mut request = to_vec(13);
-            request.push(6);
-            request.append(&mut to_vec(piece_index as u32));
-
+    let mut piece = vec![0; piece_size];
+    let mut pending: Vec<(u32, u32)> = Vec::new();
+    while !blocks_needed.is_empty() || !pending.is_empty() {
+        // request up to 5 items
+        let mut requests = Vec::new();
+        while pending.len() < 5 && !blocks_needed.is_empty() {
             let (begin, length) = blocks_needed.pop_front().unwrap();
-            request.append(&mut to_vec(begin));
-            request.append(&mut to_vec(length));
-
-            writer.write_all(&mut request).unwrap();
-
-            pending.push_back((begin, length));
+            requests.push(PeerMessage::Request {
+                index,
+                begin,
+                length,
+            });
+            pending.push((begin, length));
         }
-        writer.flush().unwrap();
+        send_messages(writer, &requests).await?;
 
         loop {
-            let message = read_peer_message(reader);
-            if message.len() == 0 || message[0] != 7 {
-                continue;
-            }
-
-            let (begin, length) = pending.pop_front().unwrap();
-
-            assert_eq!(to_vec(piece_index as u32), message[1..5]);
-            assert_eq!(to_vec(begin as u32), message[5..9]);
-            let mut data: Vec<u8> = (&message[9..]).iter().cloned().collect();
-            assert_eq!(length as usize, data.len());
-            piece.append(&mut data);
+            let (block_index, block_begin, block) = match read_message(reader).await? {
+                PeerMessage::Piece {
+                    index,
+                    begin,
+                    block,
+                } => (index, begin, block),
+                // the outstanding requests are dropped by a choke
+                PeerMessage::Choke => return Err(PeerError::Choked),
+                _ => continue,
+            };
+
+            // blocks may come back in any order, but only the ones asked for
+            let position = pending.iter().position(|&(begin, length)| {
+                block_index == index && block_begin == begin && block.len() == length as usize
+            });
+            let Some(position) = position else {
+                return Err(PeerError::UnexpectedMessage("a requested block"));
+            };
+            pending.remove(position);
+            let begin = block_begin as usize;
+            piece[begin..begin + block.len()].copy_from_slice(&block);
             break;
         }
     }
@@ -222,37 +452,36 @@ pub fn download_piece<T: Read>(
     // check hash
     let mut hasher = Sha1::new();
     hasher.update(&piece);
-    let piece_hash: Vec<u8> = hasher.finalize().iter().cloned().collect();
-    assert_eq!(torrent_info.piece_hashes[piece_index], piece_hash);
-
-    return Ok(piece);
-}
-
-fn read_peer_message<T: Read>(reader: &mut BufferedStream<T>) -> Vec<u8> {
-    let length = to_u32(reader.read_n_bytes(4).unwrap()).unwrap();
-    if length == 0 {
-        return Vec::new();
+    let piece_hash: Vec<u8> = hasher.finalize().to_vec();
+    if torrent_info.piece_hashes[piece_index] != piece_hash {
+        return Err(PeerError::HashMismatch(piece_index));
     }
-    return reader.read_n_bytes(length as usize).unwrap();
+
+    Ok(piece)
 }
 
-fn to_u32(vec: Vec<u8>) -> Option<u32> {
-    if vec.len() != 4 {
-        return None;
+// encodes messages into one buffer so they go out in a single write
+pub async fn send_messages(
+    writer: &mut (impl AsyncWrite + Unpin),
+    messages: &[PeerMessage],
+) -> Result<(), PeerError> {
+    let mut codec = PeerCodec::default();
+    let mut buffer = BytesMut::new();
+    for message in messages {
+        codec.encode(message, &mut buffer)?;
     }
-    Some(
-        ((vec[0] as u32) << 24)
-            | ((vec[1] as u32) << 16)
-            | ((vec[2] as u32) << 8)
-            | (vec[3] as u32),
-    )
+    writer.write_all(&buffer).await?;
+    writer.flush().await?;
+    Ok(())
 }
 
-fn to_vec(number: u32) -> Vec<u8> {
-    vec![
-        (number >> 24) as u8,
-        (number >> 16) as u8,
-        (number >> 8) as u8,
-        number as u8,
-    ]
+// a peer hanging up between two messages is reported as PeerError::Disconnected, hanging up in the
+// middle of one as an io error
+pub async fn read_message<T: AsyncRead + Unpin>(
+    reader: &mut AsyncBufferedStream<T>,
+) -> Result<PeerMessage, PeerError> {
+    reader
+        .read_frame(&mut PeerCodec::default())
+        .await?
+        .ok_or(PeerError::Disconnected)
 }
@@ -0,0 +1,694 @@
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    fmt::{self, Display},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{sync::mpsc, task::JoinHandle};
+
+use crate::{
+    bformat::btype::{BMap, BType},
+    rng::Rng,
+    torrent_info::TorrentInfo,
+    torrent_protocol::{self, TrackerError},
+    udp_tracker::UdpTracker,
+};
+
+// no more trackers are asked once this many peers are known
+const WANTED_PEERS: usize = 50;
+// how long to wait before trying again when no tracker answered
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+// regular announces are never closer together than this, whatever a tracker asks for
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+// retries for a udp tracker while there are other trackers to fall back on, so a dead one at the
+// front of a tier costs 45 seconds rather than hours
+const FAILOVER_RETRIES: u32 = 1;
+// shutting down doesn't wait any longer than this for the trackers to hear about it
+const STOPPED_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub struct AnnounceResponse {
+    // seconds the tracker wants us to wait before announcing again
+    pub interval: u64,
+    // announcing again any sooner than this is not allowed
+    pub min_interval: Option<u64>,
+    // to be sent back with every later announce to the same tracker
+    pub tracker_id: Option<String>,
+    // seeders and leechers in the swarm
+    pub complete: Option<u64>,
+    pub incomplete: Option<u64>,
+    pub warning_message: Option<String>,
+    pub peers: Vec<SocketAddr>,
+}
+
+impl AnnounceResponse {
+    // an http tracker's bencoded answer. a failure reason comes back as TrackerError::Failure
+    pub fn from_btype(response: &BType) -> Result<AnnounceResponse, TrackerError> {
+        let response = response
+            .as_map()
+            .ok_or(TrackerError::InvalidResponse("not a dictionary"))?;
+        if let Some(reason) = response.get("failure reason") {
+            return Err(TrackerError::Failure(string(reason, "failure reason")?));
+        }
+
+        let mut peers = Vec::new();
+        match response.get("peers") {
+            Some(BType::Bytes(compact)) => peers.extend(compact_peers(compact, false)),
+            Some(BType::List(list)) => {
+                for peer in list {
+                    peers.extend(dictionary_peer(peer)?);
+                }
+            }
+            Some(_) => return Err(TrackerError::InvalidResponse("peers")),
+            None => {}
+        }
+        if let Some(compact) = response.get("peers6") {
+            let compact = compact
+                .as_bytes()
+                .ok_or(TrackerError::InvalidResponse("peers6"))?;
+            peers.extend(compact_peers(compact, true));
+        }
+
+        Ok(AnnounceResponse {
+            interval: number(response, "interval")?
+                .ok_or(TrackerError::InvalidResponse("interval"))?,
+            min_interval: number(response, "min interval")?,
+            tracker_id: optional_string(response, "tracker id")?,
+            complete: number(response, "complete")?,
+            incomplete: number(response, "incomplete")?,
+            warning_message: optional_string(response, "warning message")?,
+            peers,
+        })
+    }
+
+    // in the form torrent_protocol::connect takes
+    pub fn peer_addresses(&self) -> Vec<String> {
+        self.peers.iter().map(SocketAddr::to_string).collect()
+    }
+}
+
+// e.g. "3 peers, 2 seeders, 1 leechers, announce again in 1800s (no sooner than 60s)"
+impl Display for AnnounceResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} peers", self.peers.len())?;
+        if let Some(complete) = self.complete {
+            write!(f, ", {complete} seeders")?;
+        }
+        if let Some(incomplete) = self.incomplete {
+            write!(f, ", {incomplete} leechers")?;
+        }
+        write!(f, ", announce again in {}s", self.interval)?;
+        if let Some(min_interval) = self.min_interval {
+            write!(f, " (no sooner than {min_interval}s)")?;
+        }
+        Ok(())
+    }
+}
+
+// a swarm as a scrape reports it
+#[derive(Debug, Clone, Copy)]
+pub struct ScrapeStats {
+    pub seeders: u64,
+    pub leechers: u64,
+    // how many times the torrent was downloaded to completion
+    pub completed: u64,
+}
+
+#[derive(Debug)]
+pub struct ScrapeResponse {
+    // by info hash, torrents the tracker doesn't know are left out
+    pub files: HashMap<Vec<u8>, ScrapeStats>,
+}
+
+impl ScrapeResponse {
+    // an http tracker's bencoded answer, the same failure reasons apply as for announces
+    pub fn from_btype(response: &BType) -> Result<ScrapeResponse, TrackerError> {
+        let response = response
+            .as_map()
+            .ok_or(TrackerError::InvalidResponse("not a dictionary"))?;
+        if let Some(reason) = response.get("failure reason") {
+            return Err(TrackerError::Failure(string(reason, "failure reason")?));
+        }
+
+        let mut files = HashMap::new();
+        if let Some(file_stats) = response.get("files") {
+            let file_stats = file_stats
+                .as_map()
+                .ok_or(TrackerError::InvalidResponse("files"))?;
+            for (info_hash, stats) in file_stats {
+                let stats = stats
+                    .as_map()
+                    .ok_or(TrackerError::InvalidResponse("files"))?;
+                let stats = ScrapeStats {
+                    seeders: number(stats, "complete")?
+                        .ok_or(TrackerError::InvalidResponse("complete"))?,
+                    leechers: number(stats, "incomplete")?
+                        .ok_or(TrackerError::InvalidResponse("incomplete"))?,
+                    // not every tracker keeps count
+                    completed: number(stats, "downloaded")?.unwrap_or(0),
+                };
+                files.insert(info_hash.clone(), stats);
+            }
+        }
+        Ok(ScrapeResponse { files })
+    }
+}
+
+// BEP 48: the announce url with "announce" at the start of its last path segment swapped for
+// "scrape", e.g. http://host/x/announce.php?key=1 becomes http://host/x/scrape.php?key=1. None
+// for trackers whose urls don't look like that, they can't be scraped
+pub fn scrape_url(announce_url: &str) -> Option<reqwest::Url> {
+    let mut url = reqwest::Url::parse(announce_url).ok()?;
+    let segment = url.path_segments()?.next_back()?;
+    let suffix = segment.strip_prefix("announce")?;
+    // the rest of the path is kept as it was, escapes included
+    let directory = &url.path()[..url.path().len() - segment.len()];
+    let path = format!("{directory}scrape{suffix}");
+    url.set_path(&path);
+    Some(url)
+}
+
+// 4 bytes of ip and 2 of port per peer, or 16 and 2 for ipv6. a partial peer at the end is dropped
+pub fn compact_peers(compact: &[u8], ipv6: bool) -> Vec<SocketAddr> {
+    let peer_size = if ipv6 { 18 } else { 6 };
+    compact
+        .chunks_exact(peer_size)
+        .map(|peer| {
+            let ip = if ipv6 {
+                IpAddr::from(Ipv6Addr::from(<[u8; 16]>::try_from(&peer[..16]).unwrap()))
+            } else {
+                IpAddr::from(Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]))
+            };
+            let port = u16::from_be_bytes([peer[peer_size - 2], peer[peer_size - 1]]);
+            SocketAddr::new(ip, port)
+        })
+        .collect()
+}
+
+// {"peer id": ..., "ip": ..., "port": ...}. peers given by hostname instead of ip are skipped
+fn dictionary_peer(peer: &BType) -> Result<Option<SocketAddr>, TrackerError> {
+    let peer = peer
+        .as_map()
+        .ok_or(TrackerError::InvalidResponse("peers"))?;
+    let ip = string(
+        peer.get("ip").ok_or(TrackerError::InvalidResponse("ip"))?,
+        "ip",
+    )?;
+    let port = number(peer, "port")?.ok_or(TrackerError::InvalidResponse("port"))?;
+    let port = u16::try_from(port).map_err(|_| TrackerError::InvalidResponse("port"))?;
+    Ok(ip.parse().ok().map(|ip: IpAddr| SocketAddr::new(ip, port)))
+}
+
+fn number(map: &BMap, key: &'static str) -> Result<Option<u64>, TrackerError> {
+    map.get(key)
+        .map(|value| {
+            value
+                .as_number()
+                .and_then(|&number| u64::try_from(number).ok())
+                .ok_or(TrackerError::InvalidResponse(key))
+        })
+        .transpose()
+}
+
+fn optional_string(map: &BMap, key: &'static str) -> Result<Option<String>, TrackerError> {
+    map.get(key).map(|value| string(value, key)).transpose()
+}
+
+fn string(value: &BType, key: &'static str) -> Result<String, TrackerError> {
+    value
+        .as_bytes()
+        .map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+        .ok_or(TrackerError::InvalidResponse(key))
+}
+
+// the trackers of a torrent as BEP 12 describes them: tiers are tried in order and the trackers
+// within a tier in random order, except that a tracker that answered moves to the front of its
+// tier so it's asked first next time
+pub struct TrackerTiers {
+    tiers: Vec<Vec<String>>,
+    // by tracker url, for the trackers that handed one out
+    tracker_ids: HashMap<String, String>,
+    // by tracker url, kept so their connection ids can be reused
+    udp_trackers: HashMap<String, UdpTracker>,
+}
+
+impl TrackerTiers {
+    pub fn new(trackers: &[Vec<String>]) -> TrackerTiers {
+        let mut rng = Rng::from_time();
+        let mut tiers = trackers.to_vec();
+        for tier in &mut tiers {
+            for i in (1..tier.len()).rev() {
+                tier.swap(i, rng.below(i + 1));
+            }
+        }
+        TrackerTiers {
+            tiers,
+            tracker_ids: HashMap::new(),
+            udp_trackers: HashMap::new(),
+        }
+    }
+
+    // goes through the trackers until enough peers are known, falling back to the next tracker
+    // or tier whenever one fails or doesn't know enough peers. the result is the first answer
+    // with the peers of every answer merged into it, or the last error if nobody answered
+    pub async fn announce(
+        &mut self,
+        torrent_info: &TorrentInfo,
+        event: AnnounceEvent,
+        progress: Progress,
+    ) -> Result<AnnounceResponse, TrackerError> {
+        let mut merged: Option<AnnounceResponse> = None;
+        let mut seen = HashSet::new();
+        let mut last_error = None;
+        'tiers: for tier_index in 0..self.tiers.len() {
+            for position in 0..self.tiers[tier_index].len() {
+                let url = self.tiers[tier_index][position].clone();
+                let mut response = match self.announce_to(&url, torrent_info, event, progress).await
+                {
+                    Ok(response) => response,
+                    Err(err) => {
+                        last_error = Some(err);
+                        continue;
+                    }
+                };
+                let tier = &mut self.tiers[tier_index];
+                tier.remove(position);
+                tier.insert(0, url.clone());
+                if let Some(tracker_id) = &response.tracker_id {
+                    self.tracker_ids.insert(url, tracker_id.clone());
+                }
+
+                response.peers.retain(|&peer| seen.insert(peer));
+                match &mut merged {
+                    Some(merged) => merged.peers.append(&mut response.peers),
+                    None => merged = Some(response),
+                }
+                if seen.len() >= WANTED_PEERS {
+                    break 'tiers;
+                }
+            }
+        }
+        // there is always at least one tracker, so no answer means there was an error
+        merged.ok_or_else(|| last_error.unwrap())
+    }
+
+    // a single tracker, over http or udp depending on its url
+    async fn announce_to(
+        &mut self,
+        url: &str,
+        torrent_info: &TorrentInfo,
+        event: AnnounceEvent,
+        progress: Progress,
+    ) -> Result<AnnounceResponse, TrackerError> {
+        let parsed_url =
+            reqwest::Url::parse(url).map_err(|_| TrackerError::UnsupportedUrl(url.to_owned()))?;
+        match parsed_url.scheme() {
+            "http" | "https" => {
+                let tracker_id = self.tracker_ids.get(url).map(String::as_str);
+                torrent_protocol::http_announce(torrent_info, url, tracker_id, event, progress)
+                    .await
+            }
+            "udp" => {
+                let announce = self
+                    .udp_tracker(url, &parsed_url)
+                    .await?
+                    .announce(
+                        &torrent_info.info_hash,
+                        torrent_protocol::PEER_ID,
+                        torrent_protocol::LISTEN_PORT,
+                        event,
+                        progress,
+                    )
+                    .await?;
+                Ok(AnnounceResponse {
+                    interval: announce.interval.into(),
+                    min_interval: None,
+                    tracker_id: None,
+                    complete: Some(announce.seeders.into()),
+                    incomplete: Some(announce.leechers.into()),
+                    warning_message: None,
+                    peers: compact_peers(&announce.peers, announce.ipv6),
+                })
+            }
+            _ => Err(TrackerError::UnsupportedUrl(url.to_owned())),
+        }
+    }
+
+    // asks the trackers in order until one of them answers. returns its url along with the answer
+    pub async fn scrape(
+        &mut self,
+        info_hashes: &[&[u8]],
+    ) -> Result<(String, ScrapeResponse), TrackerError> {
+        let mut last_error = None;
+        for url in self.tiers.concat() {
+            match self.scrape_from(&url, info_hashes).await {
+                Ok(response) => return Ok((url, response)),
+                Err(err) => last_error = Some(err),
+            }
+        }
+        // there is always at least one tracker, so no answer means there was an error
+        Err(last_error.unwrap())
+    }
+
+    async fn scrape_from(
+        &mut self,
+        url: &str,
+        info_hashes: &[&[u8]],
+    ) -> Result<ScrapeResponse, TrackerError> {
+        let parsed_url =
+            reqwest::Url::parse(url).map_err(|_| TrackerError::UnsupportedUrl(url.to_owned()))?;
+        match parsed_url.scheme() {
+            "http" | "https" => torrent_protocol::http_scrape(url, info_hashes).await,
+            "udp" => {
+                let scrapes = self
+                    .udp_tracker(url, &parsed_url)
+                    .await?
+                    .scrape(info_hashes)
+                    .await?;
+                let files = info_hashes
+                    .iter()
+                    .zip(scrapes)
+                    .map(|(info_hash, scrape)| {
+                        let stats = ScrapeStats {
+                            seeders: scrape.seeders.into(),
+                            leechers: scrape.leechers.into(),
+                            completed: scrape.completed.into(),
+                        };
+                        (info_hash.to_vec(), stats)
+                    })
+                    .collect();
+                Ok(ScrapeResponse { files })
+            }
+            _ => Err(TrackerError::UnsupportedUrl(url.to_owned())),
+        }
+    }
+
+    // connects to a udp tracker the first time it's needed
+    async fn udp_tracker(
+        &mut self,
+        url: &str,
+        parsed_url: &reqwest::Url,
+    ) -> Result<&mut UdpTracker, TrackerError> {
+        match self.udp_trackers.entry(url.to_owned()) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                let (Some(host), Some(port)) = (parsed_url.host_str(), parsed_url.port()) else {
+                    return Err(TrackerError::UnsupportedUrl(url.to_owned()));
+                };
+                // ipv6 hosts come in brackets, which the resolver doesn't want
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                let mut tracker = UdpTracker::connect(host, port).await?;
+                // the full backoff is only worth waiting out when there is nothing else to try
+                let tracker_count: usize = self.tiers.iter().map(Vec::len).sum();
+                if tracker_count > 1 {
+                    tracker.set_max_retries(FAILOVER_RETRIES);
+                }
+                Ok(entry.insert(tracker))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    // a regular announce at the tracker's interval
+    None,
+    Started,
+    Completed,
+    Stopped,
+}
+
+// the byte counts trackers are told about
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+}
+
+// running byte counts for a torrent, updated by whatever moves its data
+pub struct TransferStats {
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    left: AtomicU64,
+}
+
+impl TransferStats {
+    pub fn new(left: u64) -> TransferStats {
+        TransferStats {
+            uploaded: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+            left: AtomicU64::new(left),
+        }
+    }
+
+    pub fn add_uploaded(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    // every byte of piece data received, whether it ends up being used or not
+    pub fn add_downloaded(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    // for a piece that passed its hash check
+    pub fn add_verified(&self, bytes: u64) {
+        self.left.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    pub fn progress(&self) -> Progress {
+        Progress {
+            uploaded: self.uploaded.load(Ordering::Relaxed),
+            downloaded: self.downloaded.load(Ordering::Relaxed),
+            left: self.left.load(Ordering::Relaxed),
+        }
+    }
+}
+
+enum SessionCommand {
+    Completed,
+    Stop,
+}
+
+// keeps the trackers up to date for as long as a torrent is downloaded or seeded: started when
+// it begins, a regular announce every interval, completed when the download finishes and stopped
+// at the end
+pub struct TrackerSession {
+    commands: mpsc::UnboundedSender<SessionCommand>,
+    task: JoinHandle<()>,
+}
+
+impl TrackerSession {
+    // the peers of every answer go to peers, if given
+    pub fn start(
+        torrent_info: Arc<TorrentInfo>,
+        stats: Arc<TransferStats>,
+        peers: Option<mpsc::Sender<Vec<String>>>,
+    ) -> TrackerSession {
+        let (commands, command_receiver) = mpsc::unbounded_channel();
+        let task = tokio::spawn(run_session(torrent_info, stats, peers, command_receiver));
+        TrackerSession { commands, task }
+    }
+
+    // for when the last piece of a download has been verified
+    pub fn completed(&self) {
+        let _ = self.commands.send(SessionCommand::Completed);
+    }
+
+    // returns once the trackers have been told, or have taken too long to answer
+    pub async fn stop(self) {
+        let _ = self.commands.send(SessionCommand::Stop);
+        let _ = self.task.await;
+    }
+}
+
+async fn run_session(
+    torrent_info: Arc<TorrentInfo>,
+    stats: Arc<TransferStats>,
+    peers: Option<mpsc::Sender<Vec<String>>>,
+    mut commands: mpsc::UnboundedReceiver<SessionCommand>,
+) {
+    let mut tiers = TrackerTiers::new(&torrent_info.trackers);
+    let mut event = AnnounceEvent::Started;
+    loop {
+        let sent = event;
+        // a tracker can take hours to give up on, so commands are still listened to meanwhile
+        let announced = {
+            let announce = tiers.announce(&torrent_info, sent, stats.progress());
+            tokio::pin!(announce);
+            loop {
+                tokio::select! {
+                    result = &mut announce => break Some(result),
+                    command = commands.recv() => match command {
+                        Some(SessionCommand::Completed) => event = AnnounceEvent::Completed,
+                        Some(SessionCommand::Stop) | None => {
+                            // trackers count completed downloads, so that announce gets to finish
+                            if sent == AnnounceEvent::Completed {
+                                let _ = tokio::time::timeout(STOPPED_TIMEOUT, &mut announce).await;
+                                event = AnnounceEvent::None;
+                            }
+                            break None;
+                        }
+                    },
+                }
+            }
+        };
+        let Some(announced) = announced else {
+            break;
+        };
+
+        let wait = match announced {
+            Ok(response) => {
+                // unless the download completed while the announce was underway
+                if event == sent {
+                    event = AnnounceEvent::None;
+                }
+                if let Some(warning) = &response.warning_message {
+                    eprintln!("tracker warning: {warning}");
+                }
+                if let Some(peers) = &peers {
+                    // a download still busy with the last batch won't miss this one much, and
+                    // nobody might be listening anymore, in which case there's no one to tell
+                    let _ = peers.try_send(response.peer_addresses());
+                }
+                let min_interval = Duration::from_secs(response.min_interval.unwrap_or(0));
+                Duration::from_secs(response.interval)
+                    .max(min_interval)
+                    .max(MIN_ANNOUNCE_INTERVAL)
+            }
+            // the event is kept, so it gets through once a tracker answers again
+            Err(err) => {
+                eprintln!("tracker: {err}");
+                RETRY_INTERVAL
+            }
+        };
+
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            command = commands.recv() => match command {
+                Some(SessionCommand::Completed) => event = AnnounceEvent::Completed,
+                Some(SessionCommand::Stop) | None => break,
+            },
+        }
+    }
+    // a download that completed right before stopping hasn't been announced as such yet
+    if event == AnnounceEvent::Completed {
+        let completed = tiers.announce(&torrent_info, AnnounceEvent::Completed, stats.progress());
+        let _ = tokio::time::timeout(STOPPED_TIMEOUT, completed).await;
+    }
+    let stopped = tiers.announce(&torrent_info, AnnounceEvent::Stopped, stats.progress());
+    let _ = tokio::time::timeout(STOPPED_TIMEOUT, stopped).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    fn torrent_info(tracker_url: String) -> TorrentInfo {
+        TorrentInfo {
+            trackers: vec![vec![tracker_url]],
+            name: "test".to_owned(),
+            length: 1,
+            info_hash: vec![0; 20],
+            piece_length: 1,
+            piece_hashes: vec![vec![0; 20]],
+            files: Vec::new(),
+            multi_file: false,
+        }
+    }
+
+    // answers the next announce that comes through in full with no peers, returning its event.
+    // connections given up on before sending a request don't count
+    async fn next_event(tracker: &TcpListener) -> String {
+        loop {
+            let (mut connection, _) = tracker.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                match connection.read(&mut buffer).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(read) => request.extend_from_slice(&buffer[..read]),
+                }
+            }
+            if !request.ends_with(b"\r\n\r\n") {
+                continue;
+            }
+
+            let request = String::from_utf8_lossy(&request);
+            let path = request.split(' ').nth(1).unwrap();
+            let event = reqwest::Url::parse(&format!("http://tracker{path}"))
+                .unwrap()
+                .query_pairs()
+                .find(|(key, _)| key == "event")
+                .map_or("none".to_owned(), |(_, value)| value.into_owned());
+
+            let body = "d8:intervali1800e5:peers0:e";
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            connection.write_all(response.as_bytes()).await.unwrap();
+            return event;
+        }
+    }
+
+    #[tokio::test]
+    async fn session_announces_completed_before_stopping() {
+        let tracker = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", tracker.local_addr().unwrap());
+        let stats = Arc::new(TransferStats::new(1));
+        let session = TrackerSession::start(Arc::new(torrent_info(url)), stats, None);
+
+        let mut events = vec![next_event(&tracker).await];
+        // a download finishing is followed by stopping right away
+        session.completed();
+        let mut stopping = tokio::spawn(session.stop());
+        // whatever reached the tracker by the time stop returns
+        loop {
+            tokio::select! {
+                biased;
+                event = next_event(&tracker) => events.push(event),
+                _ = &mut stopping => break,
+            }
+        }
+        assert_eq!(events, ["started", "completed", "stopped"]);
+    }
+
+    fn scrape(announce_url: &str) -> Option<String> {
+        scrape_url(announce_url).map(String::from)
+    }
+
+    #[test]
+    fn swaps_announce_for_scrape() {
+        assert_eq!(
+            scrape("http://example.org/announce"),
+            Some("http://example.org/scrape".to_owned())
+        );
+        assert_eq!(
+            scrape("http://example.org/x/announce.php?key=1"),
+            Some("http://example.org/x/scrape.php?key=1".to_owned())
+        );
+        assert_eq!(
+            scrape("http://example.org:8080/a%20b/announce"),
+            Some("http://example.org:8080/a%20b/scrape".to_owned())
+        );
+    }
+
+    #[test]
+    fn leaves_other_urls_alone() {
+        assert_eq!(scrape("http://example.org/x/a"), None);
+        assert_eq!(scrape("http://example.org/announce/x"), None);
+        assert_eq!(scrape("http://announce.example.org"), None);
+        assert_eq!(scrape("http://example.org/?announce"), None);
+    }
+}
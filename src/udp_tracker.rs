@@ -0,0 +1,460 @@
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use thiserror::Error;
+use tokio::net::{lookup_host, UdpSocket};
+
+use crate::{
+    rng::Rng,
+    tracker::{AnnounceEvent, Progress},
+};
+
+// identifies the protocol in a connect request, and stands in for the connection id there
+const PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+// a connection id can be used for a minute after it was handed out
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+// the nth try of a request waits 15 * 2^n seconds for an answer, n going up to 8
+const BASE_TIMEOUT: Duration = Duration::from_secs(15);
+pub const MAX_RETRIES: u32 = 8;
+// as large as a udp packet gets
+const MAX_PACKET_SIZE: usize = 0x10000;
+// the most info hashes a scrape request can carry
+const MAX_SCRAPE_HASHES: usize = 74;
+
+#[derive(Debug, Error)]
+pub enum UdpTrackerError {
+    #[error("tracker connection failed: {0}")]
+    Io(#[from] io::Error),
+    #[error("tracker didn't respond")]
+    TimedOut,
+    #[error("tracker returned an error: {0}")]
+    Failure(String),
+    #[error("invalid response from tracker")]
+    InvalidResponse,
+}
+
+pub struct UdpAnnounce {
+    pub interval: u32,
+    pub leechers: u32,
+    pub seeders: u32,
+    // compact peers, 18 bytes each when talking to the tracker over ipv6 and 6 bytes otherwise
+    pub peers: Vec<u8>,
+    pub ipv6: bool,
+}
+
+pub struct UdpScrape {
+    pub seeders: u32,
+    pub completed: u32,
+    pub leechers: u32,
+}
+
+// a tracker speaking BEP 15. keeps its connection id around, so announcing again within a
+// minute skips the connect round trip
+pub struct UdpTracker {
+    socket: UdpSocket,
+    // the connection id and when it was handed out
+    connection: Option<(u64, Instant)>,
+    // sent along with announces so the tracker can tell us apart if our address changes
+    key: u32,
+    // how often an unanswered request is sent again before giving up
+    max_retries: u32,
+    rng: Rng,
+}
+
+impl UdpTracker {
+    pub async fn connect(host: &str, port: u16) -> Result<UdpTracker, UdpTrackerError> {
+        let address = lookup_host((host, port)).await?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("can't resolve {host}"))
+        })?;
+        let local_address = if address.is_ipv6() {
+            "[::]:0"
+        } else {
+            "0.0.0.0:0"
+        };
+        let socket = UdpSocket::bind(local_address).await?;
+        socket.connect(address).await?;
+        let mut rng = Rng::from_time();
+        Ok(UdpTracker {
+            socket,
+            connection: None,
+            key: rng.next_u64() as u32,
+            max_retries: MAX_RETRIES,
+            rng,
+        })
+    }
+
+    // the full backoff takes hours to run out, too long to wait with other trackers to try
+    pub fn set_max_retries(&mut self, max_retries: u32) {
+        self.max_retries = max_retries;
+    }
+
+    pub async fn announce(
+        &mut self,
+        info_hash: &[u8],
+        peer_id: &[u8],
+        port: u16,
+        event: AnnounceEvent,
+        progress: Progress,
+    ) -> Result<UdpAnnounce, UdpTrackerError> {
+        let mut body = BytesMut::new();
+        body.put_slice(info_hash);
+        body.put_slice(peer_id);
+        body.put_u64(progress.downloaded);
+        body.put_u64(progress.left);
+        body.put_u64(progress.uploaded);
+        body.put_u32(match event {
+            AnnounceEvent::None => 0,
+            AnnounceEvent::Completed => 1,
+            AnnounceEvent::Started => 2,
+            AnnounceEvent::Stopped => 3,
+        });
+        // ip address, 0 to use the one the packet came from
+        body.put_u32(0);
+        body.put_u32(self.key);
+        // number of peers wanted, -1 for the tracker's default
+        body.put_i32(-1);
+        body.put_u16(port);
+
+        let response = self.request(ACTION_ANNOUNCE, &body).await?;
+        if response.len() < 12 {
+            return Err(UdpTrackerError::InvalidResponse);
+        }
+        let mut header = &response[..12];
+        let (interval, leechers, seeders) = (header.get_u32(), header.get_u32(), header.get_u32());
+        let ipv6 = self.socket.peer_addr()?.is_ipv6();
+        let peers = response[12..].to_vec();
+        let peer_size = if ipv6 { 18 } else { 6 };
+        if !peers.chunks_exact(peer_size).remainder().is_empty() {
+            return Err(UdpTrackerError::InvalidResponse);
+        }
+        Ok(UdpAnnounce {
+            interval,
+            leechers,
+            seeders,
+            peers,
+            ipv6,
+        })
+    }
+
+    // one scrape per info hash, in the same order. takes as many requests as it needs to
+    pub async fn scrape(
+        &mut self,
+        info_hashes: &[&[u8]],
+    ) -> Result<Vec<UdpScrape>, UdpTrackerError> {
+        let mut scrapes = Vec::new();
+        for info_hashes in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            let body = info_hashes.concat();
+            let response = self.request(ACTION_SCRAPE, &body).await?;
+            if response.len() < 12 * info_hashes.len() {
+                return Err(UdpTrackerError::InvalidResponse);
+            }
+            for mut scrape in response.chunks_exact(12).take(info_hashes.len()) {
+                scrapes.push(UdpScrape {
+                    seeders: scrape.get_u32(),
+                    completed: scrape.get_u32(),
+                    leechers: scrape.get_u32(),
+                });
+            }
+        }
+        Ok(scrapes)
+    }
+
+    // sends an action with body until the tracker answers it, connecting first whenever the
+    // connection id has expired. returns the response past its action and transaction id
+    async fn request(&mut self, action: u32, body: &[u8]) -> Result<Vec<u8>, UdpTrackerError> {
+        let transaction_id = self.rng.next_u64() as u32;
+        for attempt in 0..=self.max_retries {
+            let connection_id = self.connection_id().await?;
+            let mut packet = BytesMut::new();
+            packet.put_u64(connection_id);
+            packet.put_u32(action);
+            packet.put_u32(transaction_id);
+            packet.put_slice(body);
+            if let Some(response) = self
+                .exchange(&packet, action, transaction_id, attempt)
+                .await?
+            {
+                return Ok(response);
+            }
+        }
+        Err(UdpTrackerError::TimedOut)
+    }
+
+    async fn connection_id(&mut self) -> Result<u64, UdpTrackerError> {
+        if let Some((connection_id, obtained)) = self.connection {
+            if obtained.elapsed() < CONNECTION_ID_LIFETIME {
+                return Ok(connection_id);
+            }
+        }
+
+        let transaction_id = self.rng.next_u64() as u32;
+        let mut packet = BytesMut::new();
+        packet.put_u64(PROTOCOL_ID);
+        packet.put_u32(ACTION_CONNECT);
+        packet.put_u32(transaction_id);
+        for attempt in 0..=self.max_retries {
+            let Some(response) = self
+                .exchange(&packet, ACTION_CONNECT, transaction_id, attempt)
+                .await?
+            else {
+                continue;
+            };
+            if response.len() < 8 {
+                return Err(UdpTrackerError::InvalidResponse);
+            }
+            let connection_id = (&response[..8]).get_u64();
+            self.connection = Some((connection_id, Instant::now()));
+            return Ok(connection_id);
+        }
+        Err(UdpTrackerError::TimedOut)
+    }
+
+    // sends packet once and waits out the attempt's timeout for the answer to it, None if it
+    // doesn't come. answers to earlier tries of the same request count too, they share the
+    // transaction id
+    async fn exchange(
+        &self,
+        packet: &[u8],
+        action: u32,
+        transaction_id: u32,
+        attempt: u32,
+    ) -> Result<Option<Vec<u8>>, UdpTrackerError> {
+        self.socket.send(packet).await?;
+        let deadline = tokio::time::Instant::now() + BASE_TIMEOUT * 2u32.pow(attempt);
+        let mut buffer = vec![0; MAX_PACKET_SIZE];
+        loop {
+            let Ok(read) = tokio::time::timeout_at(deadline, self.socket.recv(&mut buffer)).await
+            else {
+                return Ok(None);
+            };
+            let response = &buffer[..read?];
+            if response.len() < 8 {
+                continue;
+            }
+            let mut header = &response[..8];
+            let (response_action, response_transaction_id) = (header.get_u32(), header.get_u32());
+            // left over from some other request
+            if response_transaction_id != transaction_id {
+                continue;
+            }
+            if response_action == ACTION_ERROR {
+                let message = String::from_utf8_lossy(&response[8..]).into_owned();
+                return Err(UdpTrackerError::Failure(message));
+            }
+            if response_action != action {
+                return Err(UdpTrackerError::InvalidResponse);
+            }
+            return Ok(Some(response[8..].to_vec()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+
+    const INFO_HASH: [u8; 20] = [7; 20];
+    const PEER_ID: [u8; 20] = [9; 20];
+
+    // a stand-in tracker on a local port, driven packet by packet by the test
+    struct FakeTracker {
+        socket: UdpSocket,
+    }
+
+    impl FakeTracker {
+        async fn start() -> (FakeTracker, UdpTracker) {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let port = socket.local_addr().unwrap().port();
+            let tracker = UdpTracker::connect("127.0.0.1", port).await.unwrap();
+            (FakeTracker { socket }, tracker)
+        }
+
+        // returns (connection id, action, transaction id, body) and who sent it
+        async fn receive(&self) -> ((u64, u32, u32, Vec<u8>), SocketAddr) {
+            let mut buffer = vec![0; MAX_PACKET_SIZE];
+            let (read, from) = self.socket.recv_from(&mut buffer).await.unwrap();
+            let mut packet = &buffer[..read];
+            let header = (packet.get_u64(), packet.get_u32(), packet.get_u32());
+            ((header.0, header.1, header.2, packet.to_vec()), from)
+        }
+
+        async fn reply(&self, to: SocketAddr, action: u32, transaction_id: u32, body: &[u8]) {
+            let mut packet = BytesMut::new();
+            packet.put_u32(action);
+            packet.put_u32(transaction_id);
+            packet.put_slice(body);
+            self.socket.send_to(&packet, to).await.unwrap();
+        }
+
+        // answers a connect request with connection_id
+        async fn accept_connect(&self, connection_id: u64) {
+            let ((protocol_id, action, transaction_id, _), from) = self.receive().await;
+            assert_eq!(protocol_id, PROTOCOL_ID);
+            assert_eq!(action, ACTION_CONNECT);
+            self.reply(
+                from,
+                ACTION_CONNECT,
+                transaction_id,
+                &connection_id.to_be_bytes(),
+            )
+            .await;
+        }
+    }
+
+    fn progress() -> Progress {
+        Progress {
+            uploaded: 1,
+            downloaded: 2,
+            left: 3,
+        }
+    }
+
+    #[tokio::test]
+    async fn connects_and_announces() {
+        let (fake, mut tracker) = FakeTracker::start().await;
+        let server = tokio::spawn(async move {
+            fake.accept_connect(0xabcd).await;
+            let ((connection_id, action, transaction_id, body), from) = fake.receive().await;
+            assert_eq!(connection_id, 0xabcd);
+            assert_eq!(action, ACTION_ANNOUNCE);
+            assert_eq!(body.len(), 82);
+            assert_eq!(&body[..20], &INFO_HASH);
+            assert_eq!(&body[20..40], &PEER_ID);
+            let mut body = &body[40..];
+            assert_eq!((body.get_u64(), body.get_u64(), body.get_u64()), (2, 3, 1));
+            // started
+            assert_eq!(body.get_u32(), 2);
+            body.advance(12);
+            assert_eq!(body.get_u16(), 6881);
+
+            let mut response = BytesMut::new();
+            response.put_u32(1800);
+            response.put_u32(4);
+            response.put_u32(5);
+            response.put_slice(&[127, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x1a, 0xe2]);
+            fake.reply(from, ACTION_ANNOUNCE, transaction_id, &response)
+                .await;
+        });
+
+        let announce = tracker
+            .announce(
+                &INFO_HASH,
+                &PEER_ID,
+                6881,
+                AnnounceEvent::Started,
+                progress(),
+            )
+            .await
+            .unwrap();
+        server.await.unwrap();
+        assert_eq!(
+            (announce.interval, announce.leechers, announce.seeders),
+            (1800, 4, 5)
+        );
+        assert!(!announce.ipv6);
+        assert_eq!(announce.peers.len(), 12);
+    }
+
+    #[tokio::test]
+    async fn ignores_answers_to_other_transactions() {
+        let (fake, mut tracker) = FakeTracker::start().await;
+        let server = tokio::spawn(async move {
+            let ((_, _, transaction_id, _), from) = fake.receive().await;
+            // left over from some earlier request, then too short to mean anything
+            fake.reply(
+                from,
+                ACTION_CONNECT,
+                transaction_id.wrapping_add(1),
+                &[0; 8],
+            )
+            .await;
+            fake.socket.send_to(&[1, 2, 3], from).await.unwrap();
+            fake.reply(from, ACTION_CONNECT, transaction_id, &7u64.to_be_bytes())
+                .await;
+
+            let ((connection_id, _, transaction_id, _), from) = fake.receive().await;
+            assert_eq!(connection_id, 7);
+            fake.reply(from, ACTION_ANNOUNCE, transaction_id, &[0; 12])
+                .await;
+        });
+
+        let announce = tracker
+            .announce(&INFO_HASH, &PEER_ID, 6881, AnnounceEvent::None, progress())
+            .await
+            .unwrap();
+        server.await.unwrap();
+        assert!(announce.peers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reports_tracker_errors() {
+        let (fake, mut tracker) = FakeTracker::start().await;
+        let server = tokio::spawn(async move {
+            fake.accept_connect(1).await;
+            let ((_, _, transaction_id, _), from) = fake.receive().await;
+            fake.reply(
+                from,
+                ACTION_ERROR,
+                transaction_id,
+                b"torrent not registered",
+            )
+            .await;
+        });
+
+        let result = tracker
+            .announce(&INFO_HASH, &PEER_ID, 6881, AnnounceEvent::None, progress())
+            .await;
+        server.await.unwrap();
+        assert!(
+            matches!(result, Err(UdpTrackerError::Failure(message)) if message == "torrent not registered")
+        );
+    }
+
+    #[tokio::test]
+    async fn reuses_the_connection_id() {
+        let (fake, mut tracker) = FakeTracker::start().await;
+        let server = tokio::spawn(async move {
+            fake.accept_connect(42).await;
+            for _ in 0..2 {
+                let ((connection_id, action, transaction_id, _), from) = fake.receive().await;
+                assert_eq!((connection_id, action), (42, ACTION_ANNOUNCE));
+                fake.reply(from, ACTION_ANNOUNCE, transaction_id, &[0; 12])
+                    .await;
+            }
+        });
+
+        for _ in 0..2 {
+            tracker
+                .announce(&INFO_HASH, &PEER_ID, 6881, AnnounceEvent::None, progress())
+                .await
+                .unwrap();
+        }
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_peer_lists() {
+        let (fake, mut tracker) = FakeTracker::start().await;
+        let server = tokio::spawn(async move {
+            fake.accept_connect(1).await;
+            let ((_, _, transaction_id, _), from) = fake.receive().await;
+            fake.reply(from, ACTION_ANNOUNCE, transaction_id, &[0; 15])
+                .await;
+        });
+
+        let result = tracker
+            .announce(&INFO_HASH, &PEER_ID, 6881, AnnounceEvent::None, progress())
+            .await;
+        server.await.unwrap();
+        assert!(matches!(result, Err(UdpTrackerError::InvalidResponse)));
+    }
+}